@@ -1,20 +1,37 @@
 use std::collections::HashMap;
+use std::collections::BTreeMap;
 use super::dfa::*;
+use ::error::CompileError;
+use ::minimize::minimize;
 
 pub type BdfaStateId = isize;
+
+/*
+ * A state of a Bdfa, with a transition for every class of bytes. The
+ * answers, marks and whether it's rejected are the same as the Dfa state's
+ * it was made from.
+ */
 pub struct BdfaState
 {
-  pub next : [BdfaStateId; 256],
-  pub answer : Option<usize>
+  pub next : Vec<BdfaStateId>,
+  pub answer : Option<usize>,
+  pub line_end_answer : Option<usize>,
+  pub text_end_answer : Option<usize>,
+  pub marks : Vec<usize>,
+  pub rejected : bool,
 }
 impl BdfaState
 {
-  fn new() -> BdfaState
+  fn new(class_count: usize) -> BdfaState
   {
     BdfaState
     {
-      next: [-1; 256],
+      next: vec![-1; class_count],
       answer: None,
+      line_end_answer: None,
+      text_end_answer: None,
+      marks: Vec::new(),
+      rejected: false,
     }
   }
 }
@@ -22,45 +39,69 @@ impl BdfaState
 /*
  * A Byte-Based DFA. This module generates a DFA that can accept UTF-8 strings,
  * without using a hashtable or such. It expands the state table of another DFA
- * to decode UTF-8.
+ * to decode UTF-8, turning each range of codepoints into the ranges of bytes
+ * that encode it.
+ *
+ * Bytes that every state treats the same are put in one class, and states
+ * only have a transition per class. Classes maps each byte to its class.
+ * The starts are the Dfa's, three for every mode.
  */
 pub struct Bdfa
 {
   pub states : Vec<BdfaState>,
+  pub starts : Vec<[BdfaStateId; 3]>,
+  pub classes : [usize; 256],
+  pub class_count : usize,
 }
 impl Bdfa
 {
   fn new() -> Bdfa
   {
+    let mut classes = [0; 256];
+
+    for (byte, class) in classes.iter_mut().enumerate()
+    {
+      *class = byte;
+    }
+
     Bdfa
     {
       states: Vec::new(),
+      starts: Vec::new(),
+      classes: classes,
+      class_count: 256,
     }
   }
 
+  /*
+   * Gives the answer for the whole of text, in the first mode.
+   */
   #[allow(dead_code)]
   pub fn accepts(&self, text: &str) -> Option<usize>
   {
-    let mut cur: BdfaStateId = 0;
+    let mut cur: BdfaStateId = self.starts[0][2];
 
     for byte in text.as_bytes()
     {
-      let state = self.states.get(cur as usize).unwrap();
-
-      match state.next[*byte as usize]
+      match self.states[cur as usize].next[self.classes[*byte as usize]]
       {
         -1 => return None,
         x => cur = x,
       }
     }
 
-    return self.states.get(cur as usize).unwrap().answer;
+    return self.states[cur as usize].text_end_answer;
   }
 
-  pub fn from_dfa(dfa : &Dfa) -> Bdfa
+  pub fn from_dfa(dfa : &Dfa) -> Result<Bdfa, CompileError>
   {
     let (temp_states, translation) = TempState::convert(&dfa.states);
 
+    if temp_states.len() > MAX_STATES
+    {
+      return Err(CompileError::new(&format!("the lexer would need more than {} states.", MAX_STATES)));
+    }
+
     let mut res = Bdfa::new();
 
     for state in temp_states.iter()
@@ -68,6 +109,107 @@ impl Bdfa
       res.states.push(state.translate(&translation));
     }
 
+    for start in dfa.starts.iter()
+    {
+      res.starts.push([translation[start[0]] as BdfaStateId, translation[start[1]] as BdfaStateId, translation[start[2]] as BdfaStateId]);
+    }
+
+    res.group_bytes();
+
+    return Ok(res);
+  }
+
+  /*
+   * Puts bytes in the same class whenever every state has the same
+   * transition for them, and narrows the states to match.
+   */
+  fn group_bytes(&mut self)
+  {
+    let mut columns: HashMap<Vec<BdfaStateId>, usize> = HashMap::new();
+    let mut classes = [0; 256];
+
+    // The old class each new class was taken from
+
+    let mut taken_from = Vec::new();
+
+    for (byte, class) in classes.iter_mut().enumerate()
+    {
+      let old = self.classes[byte];
+      let column = self.states.iter().map(|x| x.next[old]).collect();
+
+      *class = *columns.entry(column).or_insert_with(||
+        {
+          taken_from.push(old);
+          taken_from.len() - 1
+        });
+    }
+
+    for state in self.states.iter_mut()
+    {
+      state.next = taken_from.iter().map(|&x| state.next[x]).collect();
+    }
+
+    self.classes = classes;
+    self.class_count = taken_from.len();
+  }
+
+  /*
+   * Gives the smallest Bdfa that lexes the same as this one. Even from a
+   * minimal Dfa, expanding to bytes can leave states that act the same.
+   */
+  pub fn minimize(&self) -> Bdfa
+  {
+    let transitions: Vec<Vec<(usize, usize)>> = self.states.iter().map(|state|
+      state.next.iter().enumerate()
+        .filter(|&(_, &target)| target != -1)
+        .map(|(class, &target)| (class, target as usize))
+        .collect())
+      .collect();
+
+    let answers: Vec<_> = self.states.iter().map(|x| (x.answer, x.line_end_answer, x.text_end_answer, &x.marks, x.rejected)).collect();
+
+    let blocks = minimize(&transitions, &answers);
+
+    // Keep the first state of every block
+
+    let mut res = Bdfa::new();
+
+    res.classes = self.classes;
+    res.class_count = self.class_count;
+
+    for (id, state) in self.states.iter().enumerate()
+    {
+      if blocks[id] < res.states.len()
+      {
+        continue;
+      }
+
+      let mut resulting_state = BdfaState::new(self.class_count);
+
+      for (i, &target) in state.next.iter().enumerate()
+      {
+        if target != -1
+        {
+          resulting_state.next[i] = blocks[target as usize] as BdfaStateId;
+        }
+      }
+      resulting_state.answer = state.answer;
+      resulting_state.line_end_answer = state.line_end_answer;
+      resulting_state.text_end_answer = state.text_end_answer;
+      resulting_state.marks = state.marks.clone();
+      resulting_state.rejected = state.rejected;
+
+      res.states.push(resulting_state);
+    }
+
+    let block = |x: BdfaStateId| blocks[x as usize] as BdfaStateId;
+
+    res.starts = self.starts.iter().map(|x| [block(x[0]), block(x[1]), block(x[2])]).collect();
+
+    // Merging states can leave more bytes that act the same
+
+    res.group_bytes();
+
     return res;
   }
 }
@@ -84,7 +226,7 @@ impl Bdfa
  * This would be easier if we were only dealing with an in memory linked structure.
  */
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 enum TempTransition
 {
   None,
@@ -94,7 +236,11 @@ enum TempTransition
 struct TempState
 {
   next : [TempTransition; 256],
-  answer : Option<usize>
+  answer : Option<usize>,
+  line_end_answer : Option<usize>,
+  text_end_answer : Option<usize>,
+  marks : Vec<usize>,
+  rejected : bool,
 }
 impl TempState
 {
@@ -104,141 +250,130 @@ impl TempState
     {
       next: [TempTransition::None; 256],
       answer: None,
+      line_end_answer: None,
+      text_end_answer: None,
+      marks: Vec::new(),
+      rejected: false,
     }
   }
 
-  fn translate(&self, translation: &HashMap<DfaStateId, usize>) -> BdfaState
+  fn translate(&self, translation: &[usize]) -> BdfaState
   {
-    let mut result = BdfaState::new();
+    let mut result = BdfaState::new(256);
 
     for (i, transition) in self.next.iter().enumerate()
     {
       result.next[i] = match *transition {
           TempTransition::None => -1,
           TempTransition::Translated(x) => x as BdfaStateId,
-          TempTransition::Untranslated(x) => *translation.get(&x).unwrap() as BdfaStateId,
+          TempTransition::Untranslated(x) => translation[x] as BdfaStateId,
         };
     }
     result.answer = self.answer;
+    result.line_end_answer = self.line_end_answer;
+    result.text_end_answer = self.text_end_answer;
+    result.marks = self.marks.clone();
+    result.rejected = self.rejected;
 
     return result;
   }
 
-  fn convert(states: &Vec<DfaState>) -> (Vec<TempState>, HashMap<DfaStateId, usize>)
+  fn convert(states: &Vec<DfaState>) -> (Vec<TempState>, Vec<usize>)
   {
     let mut output = Vec::new();
-    let mut translation = HashMap::new();
+    let mut translation = Vec::new();
+
+    // Continuation states are hash-consed across every state, since many
+    // states read the same kinds of multi-byte chars into the same places
+
+    let mut shared = HashMap::new();
 
     // Convert every state, keeping a translation marking where the original maps to
 
-    for (i, s) in states.iter().enumerate()
+    for s in states.iter()
     {
-      let root_index = output.len();
-      let decoded_transitions = s.next.iter().map(|x| (EncodeUtf8::new(*x.0), *x.1)).collect();
+      let mut sequences = Vec::new();
+
+      for &(range, transition) in s.next.iter()
+      {
+        for bytes in range.utf8_sequences()
+        {
+          sequences.push((bytes, transition));
+        }
+      }
 
-      TempState::emit(
-        decoded_transitions,
-        &mut output);
+      let mut root = TempState::emit(
+        sequences.iter().map(|x| (&x.0[..], x.1)).collect(),
+        &mut output,
+        &mut shared);
 
-      translation.insert(i, root_index);
-      output.get_mut(root_index).unwrap().answer = s.answer;
+      root.answer = s.answer;
+      root.line_end_answer = s.line_end_answer;
+      root.text_end_answer = s.text_end_answer;
+      root.marks = s.marks.clone();
+      root.rejected = s.rejected;
+
+      translation.push(output.len());
+      output.push(root);
     }
 
     return (output, translation)
   }
 
-  fn emit(mut transition_list: Vec<(EncodeUtf8, DfaStateId)>, output: &mut Vec<TempState>)
+  /*
+   * Builds the state that starts reading a list of byte range sequences,
+   * each leading to a Dfa state. The states for the rest of the sequences
+   * are added to the output first. Two sequences only continue from the
+   * same state if their first byte ranges are the same, which the UTF-8
+   * splitting guarantees for overlapping ones. A continuation state that's
+   * identical to one already in shared, like "any continuation byte, then
+   * state N", is reused rather than added again.
+   */
+  fn emit(transition_list: Vec<(&[(u8, u8)], DfaStateId)>, output: &mut Vec<TempState>, shared: &mut HashMap<Vec<TempTransition>, usize>) -> TempState
   {
-    // A list of unfinished transitions by root prefix
+    // A list of unfinished transitions by first byte range
 
-    let mut deferred = HashMap::<u8, Vec<(EncodeUtf8, DfaStateId)>>::new();
+    let mut deferred = BTreeMap::<(u8, u8), Vec<(&[(u8, u8)], DfaStateId)>>::new();
 
-    // Build the state from this step from transitions with only one byte left
-    // Mark the transitions that we need to handle with a new state
+    // Transitions with only one byte range left link back to the original dfa
 
     let mut cur = TempState::new();
 
-    for (mut bytes, transition) in transition_list.drain(0..)
+    for (bytes, transition) in transition_list
     {
-      if bytes.as_slice().len() == 1
-      {
-        // We are at the end of the byte string, link back to the original dfa
+      let (low, high) = bytes[0];
 
-        let byte = bytes.next().unwrap();
-        cur.next[byte as usize] = TempTransition::Untranslated(transition);
-      }
-      else if bytes.as_slice().len() > 1
+      if bytes.len() == 1
       {
-        // We have to make another state for this, mark it for later
-
-        let byte = bytes.next().unwrap();
-
-        if deferred.contains_key(&byte)
+        for byte in (low as usize)..(high as usize + 1)
         {
-          deferred.get_mut(&byte).unwrap().push((bytes, transition));
-        }
-        else
-        {
-          deferred.insert(byte, vec![(bytes, transition)]);
+          cur.next[byte] = TempTransition::Untranslated(transition);
         }
       }
       else
       {
-        unreachable!();
+        deferred.entry(bytes[0]).or_insert_with(Vec::new).push((&bytes[1..], transition));
       }
     }
 
-    // Add the state
-
-    let root_index = output.len();
-    output.push(cur);
+    // Create and link to the states for the rest of the sequences
 
-    // Create and link to all the new states from common prefixes
-
-    for (byte, transitions) in deferred.drain()
+    for ((low, high), transitions) in deferred
     {
-      let index = output.len();
-
-      TempState::emit(transitions, output);
-      output.get_mut(root_index).unwrap().next[byte as usize] = TempTransition::Translated(index);
-    }
-  }
-}
-
-struct EncodeUtf8
-{
-  buffer: [u8; 4],
-  len: usize,
-  cur: usize,
-}
-impl EncodeUtf8
-{
-  fn new(character: char) -> EncodeUtf8
-  {
-    let mut res = EncodeUtf8 {
-      buffer: [0; 4],
-      len: 0,
-      cur: 0,
-    };
-
-    res.len = character.encode_utf8(&mut res.buffer).len();
+      let state = TempState::emit(transitions, output, shared);
 
-    res
-  }
+      let index = *shared.entry(state.next.to_vec()).or_insert_with(||
+        {
+          output.push(state);
+          output.len() - 1
+        });
 
-  fn next(&mut self) -> Option<u8>
-  {
-    if self.cur >= self.len {
-      return None;
+      for byte in (low as usize)..(high as usize + 1)
+      {
+        cur.next[byte] = TempTransition::Translated(index);
+      }
     }
 
-    let res = self.buffer[self.cur];
-    self.cur += 1;
-    return Some(res);
-  }
-
-  fn as_slice(&self) -> &[u8]
-  {
-    &self.buffer[self.cur..self.len]
+    return cur;
   }
 }
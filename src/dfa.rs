@@ -1,16 +1,49 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::collections::BTreeMap;
 use std::collections::VecDeque;
-use std::collections::BinaryHeap;
 use std::iter::Iterator;
 
 use ::ndfa::*;
+use ::error::CompileError;
+use ::range::{CharRange, partition};
+use ::minimize::minimize;
+
+/*
+ * The most states an automaton is allowed to have. Some regexes blow up
+ * exponentially when made deterministic, so this stops the build long
+ * before it runs out of memory.
+ */
+pub const MAX_STATES: usize = 1 << 20;
 
 pub type DfaStateId = usize;
+
+/*
+ * A state of a Dfa. The transitions are sorted by range, and the ranges
+ * never overlap, so a char leads to at most one state. Neighbouring ranges
+ * always lead to different states.
+ *
+ * Tokens ending in `$` can only be accepted before a newline or at the end
+ * of the text, and ones ending in `\z` only at the end of the text, so a
+ * state has an answer for each of those as well, which is the same as its
+ * answer unless one of those tokens ends there.
+ *
+ * Marks holds the tokens with trailing context whose own pattern ends in
+ * this state, sorted. When one of them is accepted, the match ends where
+ * the lexer last passed a state marked with it.
+ *
+ * A rejected state is reached by reading a char that a token with
+ * not-followed-by can't be followed by, right after that token would have
+ * been accepted. Lexers give an error there, unless the state has an
+ * answer of its own or something longer matches.
+ */
 pub struct DfaState
 {
-  pub next : BTreeMap<char, DfaStateId>,
-  pub answer : Option<usize>
+  pub next : Vec<(CharRange, DfaStateId)>,
+  pub answer : Option<usize>,
+  pub line_end_answer : Option<usize>,
+  pub text_end_answer : Option<usize>,
+  pub marks : Vec<usize>,
+  pub rejected : bool,
 }
 impl DfaState
 {
@@ -18,9 +51,54 @@ impl DfaState
   {
     DfaState
     {
-      next: BTreeMap::new(),
+      next: Vec::new(),
       answer: None,
+      line_end_answer: None,
+      text_end_answer: None,
+      marks: Vec::new(),
+      rejected: false,
+    }
+  }
+
+  /*
+   * Adds a transition after all the others. If it touches the last one and
+   * leads to the same state, the two are joined up instead.
+   */
+  fn push(&mut self, range: CharRange, transition: DfaStateId)
+  {
+    if let Some(last) = self.next.last_mut()
+    {
+      if last.1 == transition && last.0.precedes(&range)
+      {
+        last.0.end = range.end;
+        return;
+      }
     }
+
+    self.next.push((range, transition));
+  }
+
+  /*
+   * Finds the state a char leads to, if any.
+   */
+  pub fn get(&self, letter: char) -> Option<DfaStateId>
+  {
+    return self.next
+      .binary_search_by(|&(range, _)|
+        if range.end < letter
+        {
+          Ordering::Less
+        }
+        else if range.start > letter
+        {
+          Ordering::Greater
+        }
+        else
+        {
+          Ordering::Equal
+        })
+      .ok()
+      .map(|i| self.next[i].1);
   }
 }
 
@@ -29,10 +107,16 @@ impl DfaState
  * that takes a Unicode codepoint and gives the next state's index in the list.
  * Each state also has an optional answer field to indicate whether this is
  * an accepting state or not.
+ *
+ * Every mode of the Ndfa it was built from has three start states, for
+ * tokens starting in the middle of a line, at the start of a line, and at
+ * the start of the text, in that order. They only differ for tokens
+ * starting with `^` or `\A`.
  */
 pub struct Dfa
 {
   pub states : Vec<DfaState>,
+  pub starts : Vec<[DfaStateId; 3]>,
 }
 impl Dfa
 {
@@ -41,74 +125,111 @@ impl Dfa
     Dfa
     {
       states: Vec::new(),
+      starts: Vec::new(),
     }
   }
 
-  #[allow(dead_code)]
+  /*
+   * Gives the answer for the whole of text, in the first mode.
+   */
   pub fn accepts(&self, text: &str) -> Option<usize>
   {
-    let mut cur: DfaStateId = 0;
+    let mut cur: DfaStateId = self.starts[0][2];
 
     for letter in text.chars()
     {
-      let state = self.states.get(cur).unwrap();
-
-      match state.next.get(&letter)
+      match self.states[cur].get(letter)
       {
-        Some(next) => cur = *next,
+        Some(next) => cur = next,
         None => return None,
       }
     }
 
-    return self.states.get(cur).unwrap().answer;
+    return self.states[cur].text_end_answer;
   }
 
-  pub fn from_ndfa(ndfa : &Ndfa) -> Dfa
+  pub fn from_ndfa(ndfa : &Ndfa) -> Result<Dfa, CompileError>
   {
     let mut to_visit: VecDeque<NdfaStateIdSet> = VecDeque::new();
     let mut visited: HashMap<NdfaStateIdSet, usize> = HashMap::new();
 
     let mut result: Dfa = Dfa::new();
+    let mut closures = Closures::new(ndfa);
+
+    for &id in ndfa.starts.iter()
+    {
+      let middle = closures.get(id).clone();
+      let line = closures.anchored(&middle, &[Anchor::StartLine]);
+      let text = closures.anchored(&middle, &[Anchor::StartLine, Anchor::StartText]);
 
-    let start = ndfa.e_closure(0);
-    visited.insert(start.clone(), 0);
-    to_visit.push_back(start);
+      let mut starts = [0; 3];
 
-    while !to_visit.is_empty()
+      for (i, start) in [middle, line, text].iter().enumerate()
+      {
+        starts[i] = *visited.entry(start.clone()).or_insert_with(||
+          {
+            to_visit.push_back(start.clone());
+            to_visit.len() - 1
+          });
+      }
+
+      result.starts.push(starts);
+    }
+
+    // If several answers are possible, the one with the lowest rank wins
+
+    let answer = |sset: &NdfaStateIdSet| sset.iter()
+      .filter_map(|&x|
+      {
+        let state = &ndfa.states[x];
+        state.answer.map(|answer| (state.rank, answer))
+      })
+      .min()
+      .map(|(_, answer)| answer);
+
+    while let Some(current_sset) = to_visit.pop_front()
     {
-      let current_sset = to_visit.pop_front().unwrap();
+      if result.states.len() + to_visit.len() > MAX_STATES
+      {
+        return Err(CompileError::new(&format!("the lexer would need more than {} states.", MAX_STATES)));
+      }
+
+      // A token's not-followed-by only applies where that token is the one
+      // accepted, not where another token wins over it
+
+      let accepted = answer(&current_sset);
+      let current_sset: NdfaStateIdSet = current_sset.into_iter()
+        .filter(|&x| ndfa.states[x].restricts.map_or(true, |x| Some(x) == accepted))
+        .collect();
 
       // Grab every transition arrow into a big list
 
-      let mut all_transitions: Vec<(char, NdfaStateId)> = Vec::new();
+      let mut all_transitions: Vec<(CharRange, NdfaStateId)> = Vec::new();
 
       for id in current_sset.iter()
       {
-        let state = ndfa.states.get(*id).unwrap();
-
-        for (letter, id) in state.next.iter()
-        {
-          all_transitions.push((*letter, *id));
-        }
+        all_transitions.extend(ndfa.states[*id].next.iter().cloned());
       }
 
-      // Group transition arrows by which token they are used for
-      // This creates essentially what the next big state is for
-      // if this token was read.
+      // Split the arrows' ranges so they don't overlap. Every piece gets
+      // the states any of the arrows covering it lead to, which is
+      // essentially what the next big state is for if a char in it was read
 
-      let mut grouped_transitions: HashMap<char, NdfaStateIdSet> = HashMap::new();
+      let mut grouped_transitions: Vec<(CharRange, NdfaStateIdSet)> = Vec::new();
 
-      for (letter, index) in all_transitions
+      for (range, indices) in partition(&all_transitions)
       {
-        if !grouped_transitions.contains_key(&letter)
-        {
-          grouped_transitions.insert(letter, NdfaStateIdSet::new());
-        }
+        let mut sset = NdfaStateIdSet::new();
 
-        for id in ndfa.e_closure(index).iter()
+        for index in indices
         {
-          grouped_transitions.get_mut(&letter).unwrap().insert(*id);
+          sset.extend_from_slice(closures.get(index));
         }
+
+        sset.sort();
+        sset.dedup();
+
+        grouped_transitions.push((range, sset));
       }
 
       // We can now construct the new state by 'recursing'
@@ -117,34 +238,117 @@ impl Dfa
 
       // Build the transitions, enqueuing work that needs to be done
 
-      for (letter, sset) in grouped_transitions
+      for (range, sset) in grouped_transitions
       {
-        if visited.contains_key(&sset)
+        let transition = match visited.get(&sset)
         {
-          let transition = *visited.get(&sset).unwrap();
+          Some(&transition) => transition,
+          None =>
+          {
+            let future_index = (result.states.len() + 1) + to_visit.len();
 
-          resulting_state.next.insert(letter, transition);
-        }
-        else
-        {
-          let future_index = (result.states.len() + 1) + to_visit.len();
+            visited.insert(sset.clone(), future_index);
+            to_visit.push_back(sset);
 
-          resulting_state.next.insert(letter, future_index);
+            future_index
+          }
+        };
 
-          visited.insert(sset.clone(), future_index);
-          to_visit.push_back(sset.clone());
-        }
+        resulting_state.push(range, transition);
+      }
+
+      // A token's pattern can't end again once its trailing context has
+      // started, or the lexers couldn't tell which end to go back to
+
+      resulting_state.marks = current_sset.iter().filter_map(|&x| ndfa.states[x].mark).collect();
+      resulting_state.marks.sort();
+      resulting_state.marks.dedup();
+
+      if current_sset.iter().any(|&x| ndfa.states[x].in_context.map_or(false, |x| resulting_state.marks.contains(&x)))
+      {
+        return Err(CompileError::new("its trailing context can start before its own pattern is done, so where it ends would be ambiguous."));
       }
 
-      // Detect whether this should be accept or not
+      // Detect whether this should be accept or not. The end of the text is
+      // the end of a line too
 
-      resulting_state.answer = current_sset.iter().filter_map(|&x| ndfa.states.get(x).unwrap().answer).collect::<BinaryHeap<_>>().pop();
+      resulting_state.answer = accepted;
+      resulting_state.line_end_answer = answer(&closures.anchored(&current_sset, &[Anchor::EndLine]));
+      resulting_state.text_end_answer = answer(&closures.anchored(&current_sset, &[Anchor::EndLine, Anchor::EndText]));
 
       // Add it
 
+      resulting_state.rejected = current_sset.iter().any(|&x| ndfa.states[x].rejects);
+
       result.states.push(resulting_state);
     };
 
+    return Ok(result);
+  }
+
+  /*
+   * Gives the smallest Dfa that lexes the same as this one. Its alphabet is
+   * every piece of codepoints that no range boundary falls inside of.
+   */
+  pub fn minimize(&self) -> Dfa
+  {
+    let mut boundaries: Vec<u32> = Vec::new();
+
+    for state in self.states.iter()
+    {
+      for &(range, _) in state.next.iter()
+      {
+        boundaries.push(range.start as u32);
+        boundaries.push(range.end as u32 + 1);
+      }
+    }
+
+    boundaries.sort();
+    boundaries.dedup();
+
+    // A range reads every piece from the one at its start up to its end
+
+    let symbol = |x: u32| boundaries.binary_search(&x).unwrap();
+
+    let transitions: Vec<Vec<(usize, usize)>> = self.states.iter().map(|state|
+      state.next.iter()
+        .flat_map(|&(range, target)|
+          (symbol(range.start as u32)..symbol(range.end as u32 + 1)).map(move |x| (x, target)))
+        .collect())
+      .collect();
+
+    let answers: Vec<_> = self.states.iter().map(|x| (x.answer, x.line_end_answer, x.text_end_answer, &x.marks, x.rejected)).collect();
+
+    let blocks = minimize(&transitions, &answers);
+
+    // Keep the first state of every block
+
+    let mut result = Dfa::new();
+
+    for (id, state) in self.states.iter().enumerate()
+    {
+      if blocks[id] < result.states.len()
+      {
+        continue;
+      }
+
+      let mut resulting_state = DfaState::new();
+
+      for &(range, target) in state.next.iter()
+      {
+        resulting_state.push(range, blocks[target]);
+      }
+      resulting_state.answer = state.answer;
+      resulting_state.line_end_answer = state.line_end_answer;
+      resulting_state.text_end_answer = state.text_end_answer;
+      resulting_state.marks = state.marks.clone();
+      resulting_state.rejected = state.rejected;
+
+      result.states.push(resulting_state);
+    }
+
+    result.starts = self.starts.iter().map(|x| [blocks[x[0]], blocks[x[1]], blocks[x[2]]]).collect();
+
     return result;
   }
 }
@@ -0,0 +1,124 @@
+use std::fmt;
+
+/*
+ * Errors and warnings found while reading a spec. Nothing stops at the
+ * first problem, everything is collected and printed together, errors and
+ * warnings in the order they were found.
+ */
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Severity
+{
+  Error,
+  Warning,
+}
+
+/*
+ * A position in a .rlx file, along with the chain of includes that led to
+ * that file, innermost first. A file of None is stdin. The line and column
+ * start at 1, and the snippet is the whole line, which gets `length` carets
+ * under it starting at the column.
+ */
+#[derive(Clone, Debug)]
+pub struct Location
+{
+  pub file : Option<String>,
+  pub line : usize,
+  pub column : usize,
+  pub snippet : String,
+  pub length : usize,
+  pub included_from : Vec<(Option<String>, usize)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic
+{
+  pub severity : Severity,
+  pub location : Option<Location>,
+  pub message : String,
+}
+
+fn format_line(file: &Option<String>, line: usize) -> String
+{
+  match *file
+  {
+    Some(ref name) => format!("line {} of {}", line, name),
+    None => format!("line {}", line),
+  }
+}
+
+impl fmt::Display for Diagnostic
+{
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+  {
+    let severity = match self.severity
+    {
+      Severity::Error => "error",
+      Severity::Warning => "warning",
+    };
+
+    let location = match self.location
+    {
+      Some(ref x) => x,
+      None => return write!(f, "{}: {}", severity, self.message),
+    };
+
+    match location.file
+    {
+      Some(ref name) => write!(f, "{} on line {}, column {} of {}: {}", severity, location.line, location.column, name, self.message)?,
+      None => write!(f, "{} on line {}, column {}: {}", severity, location.line, location.column, self.message)?,
+    }
+
+    // Show the line with the columns underlined. Tabs are kept so the
+    // carets still line up
+
+    let indent: String = location.snippet.chars().take(location.column - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+
+    write!(f, "\n  {}", location.snippet)?;
+    write!(f, "\n  {}{}", indent, "^".repeat(location.length.max(1)))?;
+
+    for &(ref file, line) in location.included_from.iter()
+    {
+      write!(f, "\n  included from {}", format_line(file, line))?;
+    }
+
+    return Ok(());
+  }
+}
+
+pub struct Diagnostics
+{
+  pub list : Vec<Diagnostic>,
+}
+impl Diagnostics
+{
+  pub fn new() -> Diagnostics
+  {
+    Diagnostics { list: Vec::new() }
+  }
+
+  pub fn error(&mut self, location: Option<Location>, message: &str)
+  {
+    self.list.push(Diagnostic
+    {
+      severity: Severity::Error,
+      location: location,
+      message: String::from(message),
+    });
+  }
+
+  pub fn warning(&mut self, location: Option<Location>, message: &str)
+  {
+    self.list.push(Diagnostic
+    {
+      severity: Severity::Warning,
+      location: location,
+      message: String::from(message),
+    });
+  }
+
+  pub fn has_errors(&self) -> bool
+  {
+    self.list.iter().any(|x| x.severity == Severity::Error)
+  }
+}
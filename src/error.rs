@@ -0,0 +1,37 @@
+use std::fmt;
+
+/*
+ * Why a spec couldn't be built into a lexer. This is for problems that only
+ * show up when building the automata, like a regex construct the builders
+ * don't support, rather than mistakes in the .rlx file itself. Token is the
+ * name of the token the problem is in, when there is one.
+ */
+#[derive(Debug)]
+pub struct CompileError
+{
+  pub token : Option<String>,
+  pub message : String,
+}
+impl CompileError
+{
+  pub fn new(message: &str) -> CompileError
+  {
+    CompileError
+    {
+      token: None,
+      message: String::from(message),
+    }
+  }
+}
+
+impl fmt::Display for CompileError
+{
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+  {
+    match self.token
+    {
+      Some(ref name) => write!(f, "token {}: {}", name, self.message),
+      None => write!(f, "{}", self.message),
+    }
+  }
+}
@@ -1,30 +1,78 @@
-use regex_syntax::Expr;
-
-use ::ndfa::*;
-use ::dfa::*;
+use ::spec::*;
+use ::error::CompileError;
 use ::bdfa::*;
+use super::{Layout, Width};
+use super::comb;
 
-pub fn output_lex(tokens: Vec<(String, Expr)>, prefix: String) -> String
+pub fn output_lex(spec: &Spec, bdfa: &Bdfa, prefix: String, layout: Layout) -> Result<String, CompileError>
 {
-  let names: Vec<&str> = tokens.iter().map(|x| &x.0 as &str).collect();
-  let regexes: Vec<&Expr> = tokens.iter().map(|x| &x.1).collect();
-
-  let ndfa = Ndfa::from_regexes(regexes);
-  let dfa = Dfa::from_ndfa(&ndfa);
-  let bdfa = Bdfa::from_dfa(&dfa);
-
   let source_text = String::from(include_str!("templates/lex.c"));
+  let (marks, mark_lists) = build_c_mark_tables(bdfa);
 
-  return source_text
+  return Ok(source_text
+    .replace("$c-tables$", &build_c_tables(bdfa, layout)?)
     .replace("$prefix$", &prefix)
-    .replace("$c-token-enum$", &build_c_token_enum(&prefix, &names))
-    .replace("$c-state-table$", &build_c_state_table(&bdfa))
-    .replace("$c-answer-table$", &build_c_answer_table(&bdfa));
+    .replace("$c-token-enum$", &build_c_token_enum(&prefix, &spec.tokens))
+    .replace("$c-answer-table$", &build_c_answer_table(bdfa))
+    .replace("$c-skip-table$", &build_c_skip_table(&spec.tokens))
+    .replace("$c-trailing-table$", &build_c_trailing_table(&spec.tokens))
+    .replace("$c-token-count$", &spec.tokens.len().to_string())
+    .replace("$c-mark-table$", &marks)
+    .replace("$c-mark-list-table$", &mark_lists)
+    .replace("$c-mode-enum$", &build_c_mode_enum(&prefix, &spec.modes))
+    .replace("$c-start-table$", &build_c_start_table(bdfa))
+    .replace("$c-action-table$", &build_c_action_table(&prefix, spec))
+    .replace("$c-keyword-table$", &build_c_keyword_table(&prefix, &spec.tokens)));
 }
 
 // C Templates
 
-fn build_c_state_table(bdfa: &Bdfa) -> String
+fn build_c_tables(bdfa: &Bdfa, layout: Layout) -> Result<String, CompileError>
+{
+  let width = Width::fit(bdfa.states.len(), "states")?;
+
+  match layout
+  {
+    Layout::Full =>
+    {
+      return Ok(String::from(include_str!("templates/table_full.c"))
+        .replace("$c-state-type$", width.c_type())
+        .replace("$c-state-max$", width.c_max())
+        .replace("$c-class-table$", &build_c_list(&bdfa.classes))
+        .replace("$c-class-count$", &bdfa.class_count.to_string())
+        .replace("$c-state-table$", &build_c_state_table(bdfa, width)));
+    }
+    Layout::Comb =>
+    {
+      let comb = comb::pack(bdfa);
+      let base_width = Width::fit(comb.next.len(), "packed tables")?;
+      let base: Vec<isize> = comb.base.iter().map(|&x| x as isize).collect();
+
+      return Ok(String::from(include_str!("templates/table_comb.c"))
+        .replace("$c-state-type$", width.c_type())
+        .replace("$c-state-max$", width.c_max())
+        .replace("$c-base-type$", base_width.c_type())
+        .replace("$c-class-table$", &build_c_list(&bdfa.classes))
+        .replace("$c-base-table$", &base_width.list(&base))
+        .replace("$c-default-table$", &width.list(&comb.default))
+        .replace("$c-next-table$", &width.list(&comb.next))
+        .replace("$c-check-table$", &width.list(&comb.check)));
+    }
+  }
+}
+fn build_c_list<T: ToString>(list: &[T]) -> String
+{
+  let mut res = String::new();
+
+  for x in list.iter()
+  {
+    res.push_str(&x.to_string());
+    res.push_str(", ");
+  }
+
+  return res;
+}
+fn build_c_state_table(bdfa: &Bdfa, width: Width) -> String
 {
   let mut res = String::new();
 
@@ -32,13 +80,7 @@ fn build_c_state_table(bdfa: &Bdfa) -> String
   {
     let mut line = String::new();
     line.push_str("{ ");
-
-    for x in state.next.iter()
-    {
-      line.push_str(&x.to_string());
-      line.push_str(", ");
-    }
-
+    line.push_str(&width.list(&state.next));
     line.push_str(" },\n");
     res.push_str(&line);
   }
@@ -51,28 +93,169 @@ fn build_c_answer_table(bdfa: &Bdfa) -> String
 
   for state in bdfa.states.iter()
   {
-    if let Some(answer) = state.answer
+    res.push_str("{ ");
+
+    for answer in [state.answer, state.line_end_answer, state.text_end_answer].iter()
     {
-      res.push_str(&answer.to_string());
+      if let Some(answer) = *answer
+      {
+        res.push_str(&answer.to_string());
+      }
+      else if state.rejected
+      {
+        res.push_str("-2");
+      }
+      else
+      {
+        res.push_str("-1");
+      }
+      res.push_str(", ")
     }
-    else
+
+    res.push_str("}, ");
+  }
+
+  return res;
+}
+fn build_c_start_table(bdfa: &Bdfa) -> String
+{
+  let mut res = String::new();
+
+  for start in bdfa.starts.iter()
+  {
+    res.push_str("{ ");
+    res.push_str(&build_c_list(start));
+    res.push_str("}, ");
+  }
+
+  return res;
+}
+fn build_c_action_table(prefix: &str, spec: &Spec) -> String
+{
+  let mut res = String::new();
+
+  for token in spec.tokens.iter()
+  {
+    let (action, mode) = match token.action
+    {
+      Action::None => ("none", None),
+      Action::Switch(x) => ("switch", Some(x)),
+      Action::Push(x) => ("push", Some(x)),
+      Action::Pop => ("pop", None),
+    };
+
+    res.push_str(&format!("{{ {}action_{}, ", prefix, action));
+    match mode
     {
-      res.push_str("-1");
+      Some(x) => res.push_str(&format!("{}mode_{}", prefix, spec.modes[x])),
+      None => res.push_str("0"),
     }
-    res.push_str(", ")
+    res.push_str(" },\n");
   }
 
   return res;
 }
-fn build_c_token_enum(prefix: &str, names: &Vec<&str>) -> String
+fn build_c_keyword_table(prefix: &str, tokens: &Vec<Token>) -> String
 {
   let mut res = String::new();
 
-  for name in names.iter()
+  for token in tokens.iter()
   {
+    if let Some(ref keyword) = token.keyword
+    {
+      res.push_str(&format!("  {{ {}token_{}, \"{}\", {}, {}, {}token_{} }},\n",
+        prefix, tokens[keyword.base].name, keyword.text, keyword.text.len(), token.nocase as u8, prefix, token.name));
+    }
+  }
+
+  return res;
+}
+fn build_c_mode_enum(prefix: &str, modes: &Vec<String>) -> String
+{
+  let mut res = String::new();
+
+  for mode in modes.iter()
+  {
+    res.push_str(prefix);
+    res.push_str("mode_");
+    res.push_str(mode);
+    res.push_str(",\n");
+  }
+
+  return res;
+}
+fn build_c_skip_table(tokens: &Vec<Token>) -> String
+{
+  let mut res = String::new();
+
+  for token in tokens.iter()
+  {
+    res.push_str(if token.skip { "1" } else { "0" });
+    res.push_str(", ");
+  }
+
+  return res;
+}
+fn build_c_trailing_table(tokens: &Vec<Token>) -> String
+{
+  let mut res = String::new();
+
+  for token in tokens.iter()
+  {
+    res.push_str(if token.trailing.is_some() { "1" } else { "0" });
+    res.push_str(", ");
+  }
+
+  return res;
+}
+/*
+ * Every state's marks, each list ended by -1, and where in those lists
+ * each state's starts. States without marks share the first, empty list.
+ */
+fn build_c_mark_tables(bdfa: &Bdfa) -> (String, String)
+{
+  let mut marks = String::from("-1, ");
+  let mut lists = String::new();
+  let mut length = 1;
+
+  for state in bdfa.states.iter()
+  {
+    if state.marks.is_empty()
+    {
+      lists.push_str("0, ");
+      continue;
+    }
+
+    lists.push_str(&format!("{}, ", length));
+
+    marks.push_str(&build_c_list(&state.marks));
+    marks.push_str("-1, ");
+    length += state.marks.len() + 1;
+  }
+
+  return (marks, lists);
+}
+fn build_c_token_enum(prefix: &str, tokens: &Vec<Token>) -> String
+{
+  let mut res = String::new();
+
+  for token in tokens.iter()
+  {
+    if !token.doc.is_empty()
+    {
+      res.push_str("/**\n");
+      for line in token.doc.iter()
+      {
+        res.push_str(" * ");
+        res.push_str(&line.replace("*/", "* /"));
+        res.push_str("\n");
+      }
+      res.push_str(" */\n");
+    }
+
     res.push_str(prefix);
     res.push_str("token_");
-    res.push_str(*name);
+    res.push_str(&token.name);
     res.push_str(",\n");
   }
 
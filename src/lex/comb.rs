@@ -0,0 +1,128 @@
+use std::cmp::Reverse;
+
+use ::bdfa::*;
+
+/*
+ * How many of the states before a state are tried as its default. Trying
+ * every one gets slow on big lexers, and similar states are usually built
+ * close together anyway.
+ */
+const DEFAULT_CANDIDATES: usize = 256;
+
+/*
+ * How long a chain of default states can get, which bounds how many times
+ * a generated lexer has to look before it finds a transition.
+ */
+const MAX_DEPTH: usize = 4;
+
+/*
+ * A state table packed with default states and row displacement, the way
+ * yacc and flex pack theirs.
+ *
+ * Every state only keeps the transitions where it differs from its default
+ * state, or all of them if it has none. Those are laid into one array at
+ * base, offset by byte class, overlapping the other states' wherever they
+ * leave gaps. Check marks which state every entry of the array belongs to,
+ * so a lookup that doesn't find its state there falls back to the default.
+ */
+pub struct Comb
+{
+  pub base : Vec<usize>,
+  pub default : Vec<BdfaStateId>,
+  pub next : Vec<BdfaStateId>,
+  pub check : Vec<BdfaStateId>,
+}
+
+pub fn pack(bdfa: &Bdfa) -> Comb
+{
+  let states = &bdfa.states;
+
+  // Pick the default that leaves each state with the fewest entries
+
+  let mut default: Vec<BdfaStateId> = vec![-1; states.len()];
+  let mut depth = vec![0; states.len()];
+  let mut entries: Vec<Vec<(usize, BdfaStateId)>> = Vec::new();
+
+  for (id, state) in states.iter().enumerate()
+  {
+    let mut best = state.next.iter().filter(|&&x| x != -1).count();
+
+    for other in id.saturating_sub(DEFAULT_CANDIDATES)..id
+    {
+      if depth[other] >= MAX_DEPTH
+      {
+        continue;
+      }
+
+      let differences = state.next.iter().zip(states[other].next.iter()).filter(|&(a, b)| a != b).count();
+
+      if differences < best
+      {
+        best = differences;
+        default[id] = other as BdfaStateId;
+      }
+    }
+
+    // A state keeps its transitions to nowhere when its default has
+    // somewhere to go instead
+
+    let kept: Vec<(usize, BdfaStateId)> = match default[id]
+    {
+      -1 => state.next.iter().cloned().enumerate().filter(|&(_, x)| x != -1).collect(),
+      other =>
+      {
+        depth[id] = depth[other as usize] + 1;
+
+        state.next.iter().cloned().zip(states[other as usize].next.iter().cloned())
+          .enumerate()
+          .filter(|&(_, (a, b))| a != b)
+          .map(|(class, (a, _))| (class, a))
+          .collect()
+      }
+    };
+
+    entries.push(kept);
+  }
+
+  // Lay out the states with the most entries first, each at the first base
+  // where they fit
+
+  let mut order: Vec<usize> = (0..states.len()).collect();
+  order.sort_by_key(|&x| Reverse(entries[x].len()));
+
+  let mut res = Comb
+  {
+    base: vec![0; states.len()],
+    default: default,
+    next: Vec::new(),
+    check: Vec::new(),
+  };
+
+  for id in order
+  {
+    let mut base = 0;
+
+    while entries[id].iter().any(|&(class, _)| base + class < res.check.len() && res.check[base + class] != -1)
+    {
+      base += 1;
+    }
+
+    // Any class can be looked up from any base, so the array always
+    // reaches a whole row past the last one
+
+    let length = res.check.len().max(base + bdfa.class_count);
+
+    res.next.resize(length, -1);
+    res.check.resize(length, -1);
+
+    for &(class, target) in entries[id].iter()
+    {
+      res.next[base + class] = target;
+      res.check[base + class] = id as BdfaStateId;
+    }
+
+    res.base[id] = base;
+  }
+
+  return res;
+}
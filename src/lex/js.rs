@@ -1,32 +1,149 @@
-use regex_syntax::Expr;
-
-use ::ndfa::*;
+use ::spec::*;
 use ::dfa::*;
+use ::error::CompileError;
 
-pub fn output_lex(tokens: Vec<(String, Expr)>, prefix: String) -> String
+pub fn output_lex(spec: &Spec, dfa: &Dfa, prefix: String) -> Result<String, CompileError>
 {
-  let names: Vec<&str> = tokens.iter().map(|x| &x.0 as &str).collect();
-  let regexes: Vec<&Expr> = tokens.iter().map(|x| &x.1).collect();
-
-  let ndfa = Ndfa::from_regexes(regexes);
-  let dfa = Dfa::from_ndfa(&ndfa);
+  let names: Vec<&str> = spec.tokens.iter().map(|x| &x.name as &str).collect();
 
   let source_text = String::from(include_str!("templates/lex.js"));
 
-  return source_text
+  return Ok(source_text
       .replace("$prefix$", &prefix)
-      .replace("$js-state-table$", &build_js_state_table(&dfa, &names));
+      .replace("$js-token-list$", &build_js_token_list(&spec.tokens))
+      .replace("$js-skip-table$", &build_js_skip_table(&spec.tokens))
+      .replace("$js-trailing-table$", &build_js_trailing_table(&spec.tokens))
+      .replace("$js-start-table$", &build_js_start_table(dfa))
+      .replace("$js-action-table$", &build_js_action_table(&spec.tokens))
+      .replace("$js-keyword-table$", &build_js_keyword_table(&spec.tokens, false))
+      .replace("$js-nocase-keyword-table$", &build_js_keyword_table(&spec.tokens, true))
+      .replace("$js-state-table$", &build_js_state_table(dfa, &names)));
+}
+
+fn build_js_token_list(tokens: &Vec<Token>) -> String
+{
+  let mut res = String::new();
+
+  for token in tokens.iter()
+  {
+    if !token.doc.is_empty()
+    {
+      res.push_str("/**\n");
+      for line in token.doc.iter()
+      {
+        res.push_str(" * ");
+        res.push_str(&line.replace("*/", "* /"));
+        res.push_str("\n");
+      }
+      res.push_str(" */\n");
+    }
+
+    res.push_str("'");
+    res.push_str(&token.name);
+    res.push_str("',\n");
+  }
+
+  return res;
+}
+
+fn build_js_skip_table(tokens: &Vec<Token>) -> String
+{
+  let mut res = String::new();
+
+  for token in tokens.iter().filter(|x| x.skip)
+  {
+    res.push_str("'");
+    res.push_str(&token.name);
+    res.push_str("': true,\n");
+  }
+
+  return res;
+}
+
+fn build_js_trailing_table(tokens: &Vec<Token>) -> String
+{
+  let mut res = String::new();
+
+  for token in tokens.iter().filter(|x| x.trailing.is_some())
+  {
+    res.push_str("'");
+    res.push_str(&token.name);
+    res.push_str("': true,\n");
+  }
+
+  return res;
+}
+
+fn build_js_start_table(dfa: &Dfa) -> String
+{
+  let mut res = String::new();
+
+  for start in dfa.starts.iter()
+  {
+    res.push_str(&format!("[{}, {}, {}], ", start[0], start[1], start[2]));
+  }
+
+  return res;
 }
 
-fn escape_character(letter: char) -> String
+fn build_js_action_table(tokens: &Vec<Token>) -> String
 {
-  match letter
+  let mut res = String::new();
+
+  for token in tokens.iter()
   {
-    '\t' => String::from("'\\t'"),
-    '\r' => String::from("'\\r'"),
-    '\n' => String::from("'\\n'"),
-    _ => format!("'{}'", letter),
+    let action = match token.action
+    {
+      Action::None => continue,
+      Action::Switch(x) => format!("['switch', {}]", x),
+      Action::Push(x) => format!("['push', {}]", x),
+      Action::Pop => String::from("['pop']"),
+    };
+
+    res.push_str("'");
+    res.push_str(&token.name);
+    res.push_str("': ");
+    res.push_str(&action);
+    res.push_str(",\n");
   }
+
+  return res;
+}
+
+/*
+ * The keywords of each base token, either the ones compared exactly or
+ * the nocase ones, which are looked up by their text with the ASCII
+ * letters lowercased.
+ */
+fn build_js_keyword_table(tokens: &Vec<Token>, nocase: bool) -> String
+{
+  let mut res = String::new();
+
+  for (i, base) in tokens.iter().enumerate()
+  {
+    let keywords: Vec<&Token> = tokens.iter().filter(|x| x.nocase == nocase && x.keyword.as_ref().map_or(false, |k| k.base == i)).collect();
+
+    if keywords.is_empty()
+    {
+      continue;
+    }
+
+    res.push_str("'");
+    res.push_str(&base.name);
+    res.push_str("': { ");
+
+    for token in keywords
+    {
+      let text = &token.keyword.as_ref().unwrap().text;
+      let text = if nocase { text.to_ascii_lowercase() } else { text.clone() };
+
+      res.push_str(&format!("'{}': '{}', ", text, token.name));
+    }
+
+    res.push_str("},\n");
+  }
+
+  return res;
 }
 
 fn build_js_state_table(dfa: &Dfa, names: &Vec<&str>) -> String
@@ -37,16 +154,15 @@ fn build_js_state_table(dfa: &Dfa, names: &Vec<&str>) -> String
   {
     let mut line = String::new();
 
-    line.push_str("{ ");
+    line.push_str("{ next: [ ");
 
-    for (letter, transition) in state.next.iter()
+    for &(range, transition) in state.next.iter()
     {
-      line.push_str(&escape_character(*letter));
-      line.push_str(": ");
-      line.push_str(&transition.to_string());
-      line.push_str(", ");
+      line.push_str(&format!("[{}, {}, {}], ", range.start as u32, range.end as u32, transition));
     }
 
+    line.push_str("], ");
+
     if let Some(answer) = state.answer
     {
       line.push_str("answer: '");
@@ -54,6 +170,29 @@ fn build_js_state_table(dfa: &Dfa, names: &Vec<&str>) -> String
       line.push_str("', ");
     }
 
+    if state.rejected
+    {
+      line.push_str("rejected: true, ");
+    }
+
+    if !state.marks.is_empty()
+    {
+      let marks: Vec<String> = state.marks.iter().map(|&x| format!("'{}'", names[x])).collect();
+      line.push_str(&format!("marks: [{}], ", marks.join(", ")));
+    }
+
+    // The answers at the end of a line and the text are left out unless
+    // a token ending in `$` or `\z` makes them different
+
+    if state.line_end_answer != state.answer
+    {
+      line.push_str(&format!("lineEnd: '{}', ", names[state.line_end_answer.unwrap()]));
+    }
+    if state.text_end_answer != state.answer
+    {
+      line.push_str(&format!("textEnd: '{}', ", names[state.text_end_answer.unwrap()]));
+    }
+
     line.push_str(" },\n");
 
     res.push_str(&line);
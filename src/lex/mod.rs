@@ -1,9 +1,16 @@
-use regex_syntax::Expr;
+use std::cmp::Reverse;
 use std::path::Path;
 
+use ::spec::Spec;
+use ::ndfa::*;
+use ::dfa::*;
+use ::bdfa::*;
+use ::error::CompileError;
+
 mod js;
 mod rs;
 mod c;
+mod comb;
 
 pub enum Target
 {
@@ -41,12 +48,236 @@ impl Target
   }
 }
 
-pub fn output_lex(tokens: Vec<(String, Expr)>, mut prefix: String, target: Target) -> String
+/*
+ * How to break ties between tokens that match the same text and have the
+ * same explicit priority. `First` follows flex, where the token declared
+ * first wins.
+ */
+#[derive(Clone, Copy)]
+pub enum Priority
+{
+  First,
+  Last,
+}
+impl Priority
+{
+  pub fn parse(text: &str) -> Option<Priority>
+  {
+    if text == "first" {
+      Some(Priority::First)
+    } else if text == "last" {
+      Some(Priority::Last)
+    } else {
+      None
+    }
+  }
+}
+
+/*
+ * How the C and Rust lexers lay out their state tables. `Full` has a row
+ * with every byte class for every state, and `Comb` packs the rows
+ * together, which is much smaller but takes longer to look up.
+ */
+#[derive(Clone, Copy)]
+pub enum Layout
+{
+  Full,
+  Comb,
+}
+impl Layout
+{
+  pub fn parse(text: &str) -> Option<Layout>
+  {
+    if text == "full" {
+      Some(Layout::Full)
+    } else if text == "comb" {
+      Some(Layout::Comb)
+    } else {
+      None
+    }
+  }
+}
+
+/*
+ * The unsigned integer type the C and Rust lexers keep a table in. Its
+ * largest value is kept free to mean there is no state.
+ */
+#[derive(Clone, Copy)]
+enum Width
+{
+  U8,
+  U16,
+  U32,
+}
+impl Width
+{
+  /*
+   * Picks the narrowest type that can hold every value below count, and
+   * still have its largest value free. What is the table, for the error.
+   */
+  fn fit(count: usize, what: &str) -> Result<Width, CompileError>
+  {
+    if count <= u8::MAX as usize {
+      Ok(Width::U8)
+    } else if count <= u16::MAX as usize {
+      Ok(Width::U16)
+    } else if count <= u32::MAX as usize {
+      Ok(Width::U32)
+    } else {
+      Err(CompileError::new(&format!("the lexer's {} need {} values, which don't fit in 32 bits.", what, count)))
+    }
+  }
+
+  fn c_type(&self) -> &'static str
+  {
+    match *self
+    {
+      Width::U8 => "uint8_t",
+      Width::U16 => "uint16_t",
+      Width::U32 => "uint32_t",
+    }
+  }
+
+  fn c_max(&self) -> &'static str
+  {
+    match *self
+    {
+      Width::U8 => "UINT8_MAX",
+      Width::U16 => "UINT16_MAX",
+      Width::U32 => "UINT32_MAX",
+    }
+  }
+
+  fn rust_type(&self) -> &'static str
+  {
+    match *self
+    {
+      Width::U8 => "u8",
+      Width::U16 => "u16",
+      Width::U32 => "u32",
+    }
+  }
+
+  /*
+   * Writes out a list of values, with -1 written as the largest value.
+   */
+  fn list(&self, values: &[isize]) -> String
+  {
+    let none = match *self
+    {
+      Width::U8 => u8::MAX as u64,
+      Width::U16 => u16::MAX as u64,
+      Width::U32 => u32::MAX as u64,
+    };
+
+    let mut res = String::new();
+
+    for &x in values.iter()
+    {
+      if x == -1
+      {
+        res.push_str(&none.to_string());
+      }
+      else
+      {
+        res.push_str(&x.to_string());
+      }
+      res.push_str(", ");
+    }
+
+    return res;
+  }
+}
+
+/*
+ * Ranks every token, lowest rank first. Tokens with a higher explicit
+ * priority always come first, and ties are broken by declaration order.
+ */
+fn rank_tokens(spec: &Spec, priority: Priority) -> Vec<usize>
+{
+  let mut order: Vec<usize> = (0..spec.tokens.len()).collect();
+
+  order.sort_by_key(|&i|
+  {
+    let position = match priority
+    {
+      Priority::First => i as isize,
+      Priority::Last => -(i as isize),
+    };
+
+    (Reverse(spec.tokens[i].priority), position)
+  });
+
+  let mut ranks = vec![0; order.len()];
+  for (rank, &i) in order.iter().enumerate()
+  {
+    ranks[i] = rank;
+  }
+
+  return ranks;
+}
+
+/*
+ * Builds the minimal Dfa for a spec. Keywords are left out, the generated
+ * lexers look them up after matching their base token.
+ */
+fn build_dfa(spec: &Spec, priority: Priority, verbose: bool) -> Result<Dfa, CompileError>
+{
+  let ranks = rank_tokens(spec, priority);
+
+  let rules = spec.tokens.iter().enumerate().filter(|&(_, x)| x.keyword.is_none()).map(|(i, x)| Rule
+  {
+    name: &x.name,
+    regex: &x.regex,
+    trailing: x.trailing.as_ref(),
+    not_followed_by: x.not_followed_by.as_ref(),
+    answer: i,
+    mode: x.mode,
+    rank: ranks[i],
+  }).collect();
+
+  let ndfa = Ndfa::from_regexes(rules, spec.modes.len())?;
+  let dfa = Dfa::from_ndfa(&ndfa)?;
+  let minimal = dfa.minimize();
+
+  report(verbose, "dfa", dfa.states.len(), minimal.states.len());
+
+  return Ok(minimal);
+}
+
+/*
+ * Builds the byte-based Dfa the C and Rust lexers run on.
+ */
+fn build_bdfa(dfa: &Dfa, verbose: bool) -> Result<Bdfa, CompileError>
+{
+  let bdfa = Bdfa::from_dfa(dfa)?;
+  let minimal = bdfa.minimize();
+
+  report(verbose, "byte dfa", bdfa.states.len(), minimal.states.len());
+
+  return Ok(minimal);
+}
+
+/*
+ * Prints how many states an automaton has before and after minimization,
+ * when asked for with --verbose.
+ */
+fn report(verbose: bool, name: &str, before: usize, after: usize)
+{
+  if verbose
+  {
+    eprintln!("{}: {} states, {} after minimization.", name, before, after);
+  }
+}
+
+pub fn output_lex(spec: &Spec, mut prefix: String, target: Target, priority: Priority, layout: Layout, verbose: bool) -> Result<String, CompileError>
 {
+  let dfa = build_dfa(spec, priority, verbose)?;
+
   match target
   {
-    Target::Rust => rs::output_lex(tokens, prefix),
-    Target::JavaScript => js::output_lex(tokens, prefix),
+    Target::Rust => rs::output_lex(spec, &build_bdfa(&dfa, verbose)?, prefix, layout),
+    Target::JavaScript => js::output_lex(spec, &dfa, prefix),
     Target::C =>
     {
       if prefix != ""
@@ -54,7 +285,7 @@ pub fn output_lex(tokens: Vec<(String, Expr)>, mut prefix: String, target: Targe
         prefix.push('_');
       }
 
-      c::output_lex(tokens, prefix)
+      c::output_lex(spec, &build_bdfa(&dfa, verbose)?, prefix, layout)
     }
   }
 }
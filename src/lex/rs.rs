@@ -1,40 +1,96 @@
-use regex_syntax::Expr;
-
-use ::ndfa::*;
-use ::dfa::*;
+use ::spec::*;
+use ::error::CompileError;
 use ::bdfa::*;
+use super::{Layout, Width};
+use super::comb;
 
-pub fn output_lex(tokens: Vec<(String, Expr)>, prefix: String) -> String
+pub fn output_lex(spec: &Spec, bdfa: &Bdfa, prefix: String, layout: Layout) -> Result<String, CompileError>
 {
-  let names: Vec<&str> = tokens.iter().map(|x| &x.0 as &str).collect();
-  let regexes: Vec<&Expr> = tokens.iter().map(|x| &x.1).collect();
-
-  let ndfa = Ndfa::from_regexes(regexes);
-  let dfa = Dfa::from_ndfa(&ndfa);
-  let bdfa = Bdfa::from_dfa(&dfa);
-
+  let names: Vec<&str> = spec.tokens.iter().map(|x| &x.name as &str).collect();
   let source_text = String::from(include_str!("templates/lex.rs"));
 
-  return source_text
-    .replace("$tokens$", &build_tokens(&names))
+  return Ok(source_text
+    .replace("$tokens$", &build_tokens(&spec.tokens))
     .replace("$types$", &build_types(&names))
+    .replace("$parse-table$", &build_parse_table(&spec.tokens))
+    .replace("$tables$", &build_tables(bdfa, layout)?)
     .replace("$state-table-length$", &bdfa.states.len().to_string())
-    .replace("$state-table$", &build_states(&bdfa))
-    .replace("$answer-table$", &build_answers(&bdfa));
+    .replace("$answer-table$", &build_answers(bdfa))
+    .replace("$token-count$", &spec.tokens.len().to_string())
+    .replace("$skip-table$", &build_skips(&spec.tokens))
+    .replace("$trailing-table$", &build_trailing(&spec.tokens))
+    .replace("$mark-table$", &build_marks(bdfa))
+    .replace("$mode-count$", &spec.modes.len().to_string())
+    .replace("$start-table$", &build_starts(bdfa))
+    .replace("$action-table$", &build_actions(&spec.tokens))
+    .replace("$keyword-count$", &spec.tokens.iter().filter(|x| x.keyword.is_some()).count().to_string())
+    .replace("$keyword-table$", &build_keywords(&spec.tokens)));
+}
+
+// Token names from keyword lists are often Rust keywords, so they're
+// written as raw identifiers. The path keywords can't be raw, and get a
+// `_` suffix instead, which spec identifiers never have
+
+fn rust_identifier(name: &str) -> String
+{
+  const RESERVED: [&'static str; 48] = [
+    "as", "break", "const", "continue", "else", "enum", "extern", "false",
+    "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+    "mut", "pub", "ref", "return", "static", "struct", "trait", "true",
+    "type", "unsafe", "use", "where", "while", "async", "await", "dyn",
+    "abstract", "become", "box", "do", "final", "macro", "override", "priv",
+    "typeof", "unsized", "virtual", "yield", "try", "gen",
+  ];
+  const PATHS: [&'static str; 4] = ["crate", "self", "Self", "super"];
+
+  if RESERVED.contains(&name)
+  {
+    format!("r#{}", name)
+  }
+  else if PATHS.contains(&name)
+  {
+    format!("{}_", name)
+  }
+  else
+  {
+    String::from(name)
+  }
 }
 
-fn build_tokens(names: &Vec<&str>) -> String
+fn build_tokens(tokens: &Vec<Token>) -> String
 {
   let mut res = String::new();
 
-  for name in names.iter()
+  for token in tokens.iter()
   {
-    res.push_str(*name);
+    for line in token.doc.iter()
+    {
+      res.push_str("///");
+      if !line.is_empty()
+      {
+        res.push_str(" ");
+        res.push_str(line);
+      }
+      res.push_str("\n");
+    }
+
+    res.push_str(&rust_identifier(&token.name));
     res.push_str(",\n");
   }
 
   return res;
 }
+fn build_parse_table(tokens: &Vec<Token>) -> String
+{
+  let mut res = String::new();
+
+  for (i, token) in tokens.iter().enumerate()
+  {
+    res.push_str(&format!("      {} => Token::{},\n", i, rust_identifier(&token.name)));
+  }
+
+  return res;
+}
 fn build_types(names: &Vec<&str>) -> String
 {
   let mut res = String::new();
@@ -47,7 +103,51 @@ fn build_types(names: &Vec<&str>) -> String
 
   return res;
 }
-fn build_states(bdfa: &Bdfa) -> String
+fn build_tables(bdfa: &Bdfa, layout: Layout) -> Result<String, CompileError>
+{
+  let width = Width::fit(bdfa.states.len(), "states")?;
+
+  match layout
+  {
+    Layout::Full =>
+    {
+      return Ok(String::from(include_str!("templates/table_full.rs"))
+        .replace("$state-type$", width.rust_type())
+        .replace("$class-table$", &build_list(&bdfa.classes))
+        .replace("$class-count$", &bdfa.class_count.to_string())
+        .replace("$state-table$", &build_states(bdfa, width)));
+    }
+    Layout::Comb =>
+    {
+      let comb = comb::pack(bdfa);
+      let base_width = Width::fit(comb.next.len(), "packed tables")?;
+      let base: Vec<isize> = comb.base.iter().map(|&x| x as isize).collect();
+
+      return Ok(String::from(include_str!("templates/table_comb.rs"))
+        .replace("$state-type$", width.rust_type())
+        .replace("$base-type$", base_width.rust_type())
+        .replace("$class-table$", &build_list(&bdfa.classes))
+        .replace("$base-table$", &base_width.list(&base))
+        .replace("$default-table$", &width.list(&comb.default))
+        .replace("$comb-length$", &comb.next.len().to_string())
+        .replace("$next-table$", &width.list(&comb.next))
+        .replace("$check-table$", &width.list(&comb.check)));
+    }
+  }
+}
+fn build_list<T: ToString>(list: &[T]) -> String
+{
+  let mut res = String::new();
+
+  for x in list.iter()
+  {
+    res.push_str(&x.to_string());
+    res.push_str(", ");
+  }
+
+  return res;
+}
+fn build_states(bdfa: &Bdfa, width: Width) -> String
 {
   let mut res = String::new();
 
@@ -55,13 +155,7 @@ fn build_states(bdfa: &Bdfa) -> String
   {
     let mut line = String::new();
     line.push_str("[");
-
-    for x in state.next.iter()
-    {
-      line.push_str(&x.to_string());
-      line.push_str(", ");
-    }
-
+    line.push_str(&width.list(&state.next));
     line.push_str("],\n");
     res.push_str(&line);
   }
@@ -74,15 +168,106 @@ fn build_answers(bdfa: &Bdfa) -> String
 
   for state in bdfa.states.iter()
   {
-    if let Some(answer) = state.answer
+    res.push_str("[");
+
+    for answer in [state.answer, state.line_end_answer, state.text_end_answer].iter()
     {
-      res.push_str(&answer.to_string());
+      if let Some(answer) = *answer
+      {
+        res.push_str(&answer.to_string());
+      }
+      else if state.rejected
+      {
+        res.push_str("-2");
+      }
+      else
+      {
+        res.push_str("-1");
+      }
+      res.push_str(", ")
     }
-    else
+
+    res.push_str("], ");
+  }
+
+  return res;
+}
+fn build_skips(tokens: &Vec<Token>) -> String
+{
+  let mut res = String::new();
+
+  for token in tokens.iter()
+  {
+    res.push_str(if token.skip { "true" } else { "false" });
+    res.push_str(", ");
+  }
+
+  return res;
+}
+fn build_trailing(tokens: &Vec<Token>) -> String
+{
+  let mut res = String::new();
+
+  for token in tokens.iter()
+  {
+    res.push_str(if token.trailing.is_some() { "true" } else { "false" });
+    res.push_str(", ");
+  }
+
+  return res;
+}
+fn build_marks(bdfa: &Bdfa) -> String
+{
+  let mut res = String::new();
+
+  for state in bdfa.states.iter()
+  {
+    res.push_str("&[");
+    res.push_str(&build_list(&state.marks));
+    res.push_str("], ");
+  }
+
+  return res;
+}
+fn build_starts(bdfa: &Bdfa) -> String
+{
+  let mut res = String::new();
+
+  for start in bdfa.starts.iter()
+  {
+    res.push_str(&format!("[{}, {}, {}], ", start[0], start[1], start[2]));
+  }
+
+  return res;
+}
+fn build_actions(tokens: &Vec<Token>) -> String
+{
+  let mut res = String::new();
+
+  for token in tokens.iter()
+  {
+    res.push_str(&match token.action
+    {
+      Action::None => String::from("Action::None"),
+      Action::Switch(x) => format!("Action::Switch({})", x),
+      Action::Push(x) => format!("Action::Push({})", x),
+      Action::Pop => String::from("Action::Pop"),
+    });
+    res.push_str(",\n");
+  }
+
+  return res;
+}
+fn build_keywords(tokens: &Vec<Token>) -> String
+{
+  let mut res = String::new();
+
+  for (i, token) in tokens.iter().enumerate()
+  {
+    if let Some(ref keyword) = token.keyword
     {
-      res.push_str("-1");
+      res.push_str(&format!("({}, \"{}\", {}, {}),\n", keyword.base, keyword.text, token.nocase, i));
     }
-    res.push_str(", ")
   }
 
   return res;
@@ -1,11 +1,20 @@
+#include <stdint.h>
 #include <string.h>
 
+#ifndef $prefix$LEXER_MODE_STACK_SIZE
+#define $prefix$LEXER_MODE_STACK_SIZE 32
+#endif
+
 typedef struct
 {
   const char *text;
   unsigned long index;
   unsigned long length;
 
+  long mode;
+  long mode_stack[$prefix$LEXER_MODE_STACK_SIZE];
+  unsigned long mode_depth;
+
   int eof;
   int error;
 } $prefix$lexer;
@@ -14,9 +23,40 @@ enum
 {
 $c-token-enum$};
 
-char $prefix$lexer_table[][256] = {
-$c-state-table$};
-long $prefix$lexer_answer[] = { $c-answer-table$ };
+enum
+{
+$c-mode-enum$};
+
+enum
+{
+  $prefix$action_none,
+  $prefix$action_switch,
+  $prefix$action_push,
+  $prefix$action_pop,
+};
+
+$c-tables$
+long $prefix$lexer_answer[][3] = { $c-answer-table$ };
+char $prefix$lexer_skip[] = { $c-skip-table$ };
+char $prefix$lexer_trailing[] = { $c-trailing-table$ };
+long $prefix$lexer_marks[] = { $c-mark-table$ };
+unsigned long $prefix$lexer_mark_lists[] = { $c-mark-list-table$ };
+long $prefix$lexer_start[][3] = { $c-start-table$ };
+long $prefix$lexer_action[][2] = {
+$c-action-table$};
+
+typedef struct
+{
+  long base;
+  const char *text;
+  unsigned long length;
+  int nocase;
+  long type;
+} $prefix$lexer_keyword;
+
+$prefix$lexer_keyword $prefix$lexer_keywords[] = {
+$c-keyword-table$  { -1, 0, 0, 0, -1 },
+};
 
 void $prefix$lexer_init($prefix$lexer *lex, const char *text)
 {
@@ -24,10 +64,103 @@ void $prefix$lexer_init($prefix$lexer *lex, const char *text)
   lex->index = 0;
   lex->length = strlen(text);
 
+  lex->mode = 0;
+  lex->mode_depth = 0;
+
   lex->eof = lex->index >= lex->length;
   lex->error = 0;
 }
 
+/*
+ * Gives the type state i accepts with end bytes read, or -1. See DfaState in src/dfa.rs.
+ */
+static long $prefix$lexer_accepts($prefix$lexer *lex, unsigned long i, unsigned long end)
+{
+  if (end >= lex->length)
+    return $prefix$lexer_answer[i][2];
+
+  if (lex->text[end] == '\n')
+    return $prefix$lexer_answer[i][1];
+
+  return $prefix$lexer_answer[i][0];
+}
+
+/*
+ * Marks end for the trailing context tokens of state i. See DfaState in src/dfa.rs.
+ */
+static void $prefix$lexer_mark(unsigned long *marked, unsigned long i, unsigned long end)
+{
+  for (long *k = $prefix$lexer_marks + $prefix$lexer_mark_lists[i]; *k != -1; k++)
+    marked[*k] = end;
+}
+
+/*
+ * Compares two texts of the same length, ignoring the case of ASCII
+ * letters.
+ */
+static int $prefix$lexer_equal_nocase(const char *a, const char *b, unsigned long length)
+{
+  for (unsigned long i = 0; i < length; i++)
+  {
+    char x = a[i] >= 'A' && a[i] <= 'Z' ? a[i] - 'A' + 'a' : a[i];
+    char y = b[i] >= 'A' && b[i] <= 'Z' ? b[i] - 'A' + 'a' : b[i];
+
+    if (x != y)
+      return 0;
+  }
+
+  return 1;
+}
+
+/*
+ * Looks up the text of a match in the keyword table. Gives back the type
+ * of the keyword if there is one, or the type that was matched otherwise.
+ */
+static long $prefix$lexer_find_keyword(long type, const char *text, unsigned long length)
+{
+  for ($prefix$lexer_keyword *k = $prefix$lexer_keywords; k->base != -1; k++)
+  {
+    if (k->base != type || k->length != length)
+      continue;
+
+    if (k->nocase ? $prefix$lexer_equal_nocase(k->text, text, length) : memcmp(k->text, text, length) == 0)
+      return k->type;
+  }
+
+  return type;
+}
+
+/*
+ * Updates the mode stack for a matched token. Returns 0 if the stack
+ * overflows or a pop is attempted on an empty stack.
+ */
+static int $prefix$lexer_apply_action($prefix$lexer *lex, long type)
+{
+  switch ($prefix$lexer_action[type][0])
+  {
+    case $prefix$action_switch:
+      lex->mode = $prefix$lexer_action[type][1];
+      break;
+
+    case $prefix$action_push:
+      if (lex->mode_depth >= $prefix$LEXER_MODE_STACK_SIZE)
+        return 0;
+
+      lex->mode_stack[lex->mode_depth++] = lex->mode;
+      lex->mode = $prefix$lexer_action[type][1];
+      break;
+
+    case $prefix$action_pop:
+      if (lex->mode_depth == 0)
+        return 0;
+
+      lex->mode = lex->mode_stack[--lex->mode_depth];
+      break;
+  }
+
+  return 1;
+}
+
 typedef struct
 {
   long        type;
@@ -37,66 +170,102 @@ typedef struct
 
 int $prefix$lexer_next_lexeme($prefix$lexer *lex, $prefix$lexeme *out)
 {
-  if (lex->index >= lex->length)
+  for (;;)
   {
-    lex->eof = 1;
+    if (lex->index >= lex->length)
+    {
+      lex->eof = 1;
 
-    $prefix$lexeme res = { 0, 0, 0 };
+      $prefix$lexeme res = { 0, 0, 0 };
       *out = res;
 
       return 0;
-  }
+    }
 
-  unsigned long i = 0;
-  unsigned long start = lex->index;
-  unsigned long end = lex->index;
+    // Lines and the text have start states of their own, see Dfa in src/dfa.rs
 
-  long      match_type = -1;
-  unsigned long   match_end = -1;
+    unsigned long context = lex->index == 0 ? 2 : lex->text[lex->index - 1] == '\n' ? 1 : 0;
 
-  if ($prefix$lexer_answer[i] != -1)
-  {
-    match_type = $prefix$lexer_answer[i];
-    match_end = end;
-  }
+    unsigned long i = $prefix$lexer_start[lex->mode][context];
+    unsigned long start = lex->index;
+    unsigned long end = lex->index;
 
-  while (end < lex->length)
-  {
-    char letter = lex->text[end++];
+    long      match_type = -1;
+    unsigned long   match_end = -1;
+    unsigned long   marked[$c-token-count$];
 
-    if ($prefix$lexer_table[i][letter] == -1)
-      break;
+    $prefix$lexer_mark(marked, i, end);
+    long      answer = $prefix$lexer_accepts(lex, i, end);
 
-    i = $prefix$lexer_table[i][letter];
+    if (answer != -1)
+    {
+      match_type = answer;
+      match_end = $prefix$lexer_trailing[answer] ? marked[answer] : end;
+    }
 
-    if ($prefix$lexer_answer[i] != -1)
+    while (end < lex->length)
     {
-      match_type = $prefix$lexer_answer[i];
-      match_end = end;
+      long next = $prefix$lexer_next_state(i, (unsigned char) lex->text[end++]);
+
+      if (next == -1)
+        break;
+
+      i = next;
+
+      $prefix$lexer_mark(marked, i, end);
+      answer = $prefix$lexer_accepts(lex, i, end);
+
+      // A rejected state is an error unless something longer matches
+
+      if (answer == -2)
+      {
+        match_type = -2;
+      }
+      else if (answer != -1)
+      {
+        match_type = answer;
+        match_end = $prefix$lexer_trailing[answer] ? marked[answer] : end;
+      }
     }
-  }
 
-  if (match_type != -1)
-  {
-    lex->index = match_end;
-    lex->eof = lex->index >= lex->length;
+    // The error is reported at the char after the token
+
+    if (match_type == -2)
+    {
+      lex->index = match_end;
+      match_type = -1;
+    }
+
+    if (match_type != -1)
+      match_type = $prefix$lexer_find_keyword(match_type, lex->text + start, match_end - start);
+
+    if (match_type != -1 && $prefix$lexer_apply_action(lex, match_type))
+    {
+      lex->index = match_end;
+      lex->eof = lex->index >= lex->length;
+
+      // Skipped tokens are consumed without being returned
 
-    $prefix$lexeme res = {
-      match_type,
-      lex->text + start,
-      match_end - start
-    };
-    *out = res;
+      if ($prefix$lexer_skip[match_type])
+        continue;
+
+      $prefix$lexeme res = {
+        match_type,
+        lex->text + start,
+        match_end - start
+      };
+      *out = res;
 
       return 1;
-  }
-  else
-  {
-    lex->error = 1;
+    }
+    else
+    {
+      lex->error = 1;
 
-    $prefix$lexeme res = { 0, 0, 0 };
+      $prefix$lexeme res = { 0, 0, 0 };
       *out = res;
 
-    return 0;
+      return 0;
+    }
   }
 }
@@ -3,65 +3,225 @@ function $prefix$Lex(text)
   this._text = text;
   this._index = 0;
 
+  this.mode = 0;
+  this.modeStack = [];
+
   this.error = false;
   this.eof = this._index >= this._text.length;
 }
 
+$prefix$Lex.prototype.tokens = [
+$js-token-list$];
+
+$prefix$Lex.prototype.skip = {
+$js-skip-table$};
+
+$prefix$Lex.prototype.trailing = {
+$js-trailing-table$};
+
+$prefix$Lex.prototype.starts = [ $js-start-table$ ];
+
+$prefix$Lex.prototype.actions = {
+$js-action-table$};
+
+$prefix$Lex.prototype.keywords = {
+$js-keyword-table$};
+
+$prefix$Lex.prototype.nocaseKeywords = {
+$js-nocase-keyword-table$};
+
 $prefix$Lex.prototype.dfa = [
 $js-state-table$];
 
-$prefix$Lex.prototype.getNextLexeme = function()
+// Finds the state a codepoint leads to from a state, or undefined. Each
+// transition is [first, last, state], sorted and not overlapping.
+$prefix$Lex.prototype.step = function(state, code)
 {
-  if (this._index >= this._text.length)
+  var next = state.next;
+  var low = 0;
+  var high = next.length - 1;
+
+  while (low <= high)
   {
-    this.eof = true;
-    return null;
+    var mid = (low + high) >> 1;
+
+    if (next[mid][1] < code)
+      low = mid + 1;
+    else if (next[mid][0] > code)
+      high = mid - 1;
+    else
+      return next[mid][2];
   }
 
-  var state = this.dfa[0];
-  var match = null;
-  var start = this._index;
-  var end = this._index;
+  return undefined;
+};
+
+// Gives the type a state accepts at index, or undefined. See DfaState in src/dfa.rs.
+$prefix$Lex.prototype.accepts = function(state, index)
+{
+  if (index >= this._text.length && state.hasOwnProperty('textEnd'))
+    return state.textEnd;
+
+  if (this._text[index] == '\n' && state.hasOwnProperty('lineEnd'))
+    return state.lineEnd;
+
+  return state.answer;
+};
+
+// Marks end for the trailing context tokens of a state. See DfaState in src/dfa.rs.
+$prefix$Lex.prototype.mark = function(state, marked, end)
+{
+  if (state.marks == undefined)
+    return;
 
-  if (state.answer != undefined)
+  for (var i = 0; i < state.marks.length; i++)
+    marked[state.marks[i]] = end;
+};
+
+// Updates the mode stack for a matched token, returning false if a pop
+// is attempted on an empty stack.
+$prefix$Lex.prototype.applyAction = function(type)
+{
+  var action = this.actions[type];
+
+  if (action == undefined)
+    return true;
+
+  if (action[0] == 'switch')
+  {
+    this.mode = action[1];
+  }
+  else if (action[0] == 'push')
+  {
+    this.modeStack.push(this.mode);
+    this.mode = action[1];
+  }
+  else if (action[0] == 'pop')
   {
-    match = {
-      type: state.answer,
-      end: end,
-    };
+    if (this.modeStack.length == 0)
+      return false;
+
+    this.mode = this.modeStack.pop();
   }
 
-  while (end < this._text.length)
+  return true;
+};
+
+$prefix$Lex.prototype.getNextLexeme = function()
+{
+  for (;;)
   {
-    var letter = this._text[end++];
+    if (this._index >= this._text.length)
+    {
+      this.eof = true;
+      return null;
+    }
+
+    // Lines and the text have start states of their own, see Dfa in src/dfa.rs
+
+    var context = this._index == 0 ? 2 : this._text[this._index - 1] == '\n' ? 1 : 0;
 
-    if (state[letter] == undefined)
-      break;
+    var state = this.dfa[this.starts[this.mode][context]];
+    var match = null;
+    var start = this._index;
+    var end = this._index;
 
-    state = this.dfa[state[letter]];
+    // Where each trailing context token was last marked, see DfaState in src/dfa.rs
 
-    if (state.answer != undefined)
+    var marked = {};
+
+    this.mark(state, marked, end);
+
+    var answer = this.accepts(state, end);
+
+    if (answer != undefined)
     {
       match = {
-        type: state.answer,
-        end: end,
+        type: answer,
+        end: this.trailing[answer] ? marked[answer] : end,
       };
     }
-  }
 
-  if (match != null)
-  {
-    this._index = match.end;
-    this.eof = this._index >= this._text.length;
+    while (end < this._text.length)
+    {
+      var code = this._text.codePointAt(end);
+      var next = this.step(state, code);
 
-    return {
-      type: match.type,
-      lexeme: this._text.substr(start, match.end - start)
-    };
-  }
-  else
-  {
-    this.error = true;
-    return null;
+      if (next == undefined)
+        break;
+
+      end += code > 0xFFFF ? 2 : 1;
+      state = this.dfa[next];
+
+      this.mark(state, marked, end);
+
+      answer = this.accepts(state, end);
+
+      if (answer != undefined)
+      {
+        match = {
+          type: answer,
+          end: this.trailing[answer] ? marked[answer] : end,
+        };
+      }
+      else if (state.rejected)
+      {
+        // A rejected state is an error unless something longer matches
+
+        match.rejected = true;
+      }
+    }
+
+    // The error is reported at the char after the token
+
+    if (match != null && match.rejected)
+    {
+      this._index = match.end;
+      this.error = true;
+      return null;
+    }
+
+    // Matches of a keyword's base token are checked against the keywords
+
+    if (match != null && this.keywords.hasOwnProperty(match.type))
+    {
+      var keywords = this.keywords[match.type];
+      var text = this._text.substr(start, match.end - start);
+
+      if (keywords.hasOwnProperty(text))
+        match.type = keywords[text];
+    }
+
+    // Nocase keywords are looked up with the ASCII letters lowercased
+
+    if (match != null && this.nocaseKeywords.hasOwnProperty(match.type))
+    {
+      var keywords = this.nocaseKeywords[match.type];
+      var text = this._text.substr(start, match.end - start).replace(/[A-Z]/g, function (x) { return x.toLowerCase(); });
+
+      if (keywords.hasOwnProperty(text))
+        match.type = keywords[text];
+    }
+
+    if (match != null && this.applyAction(match.type))
+    {
+      this._index = match.end;
+      this.eof = this._index >= this._text.length;
+
+      // Skipped tokens are consumed without being returned
+
+      if (this.skip[match.type])
+        continue;
+
+      return {
+        type: match.type,
+        lexeme: this._text.substr(start, match.end - start)
+      };
+    }
+    else
+    {
+      this.error = true;
+      return null;
+    }
   }
 };
@@ -1,24 +1,77 @@
 use std::str::from_utf8;
 
-const TRANSITIONS: [[i8; 256]; $state-table-length$] = [
-$state-table$];
-const ANSWERS: [isize; $state-table-length$] = [ $answer-table$ ];
+$tables$
+const ANSWERS: [[isize; 3]; $state-table-length$] = [ $answer-table$ ];
+const SKIP: [bool; $token-count$] = [ $skip-table$ ];
+const TRAILING: [bool; $token-count$] = [ $trailing-table$ ];
+const MARKS: [&'static [usize]; $state-table-length$] = [ $mark-table$ ];
+const STARTS: [[usize; 3]; $mode-count$] = [ $start-table$ ];
+const ACTIONS: [Action; $token-count$] = [
+$action-table$];
+const KEYWORDS: [(isize, &'static str, bool, isize); $keyword-count$] = [
+$keyword-table$];
+
+#[derive(Clone, Copy)]
+enum Action
+{
+  None,
+  Switch(usize),
+  Push(usize),
+  Pop,
+}
 
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Token
 {
 $tokens$}
 impl Token
 {
-  fn parse(token_type: isize, text: &str) -> Token
+  fn parse(token_type: isize) -> Token
+  {
+    match token_type {
+$parse-table$      _ => unreachable!(),
+    }
+  }
+}
+
+// Gives the type a state accepts with rest coming after it, or -1. See DfaState in src/dfa.rs.
+fn accepts(state: usize, rest: &[u8]) -> isize
+{
+  match rest.first() {
+    None => ANSWERS[state][2],
+    Some(&b'\n') => ANSWERS[state][1],
+    Some(_) => ANSWERS[state][0],
+  }
+}
+
+// Looks up the text of a match in the keyword table, giving back the type
+// of the keyword if there is one, or the type that was matched otherwise.
+// Nocase keywords ignore the case of ASCII letters.
+fn find_keyword(token_type: isize, text: &[u8]) -> isize
+{
+  for &(base, keyword, nocase, keyword_type) in KEYWORDS.iter()
   {
-    unimplemented!();
+    let equal = if nocase {
+      keyword.as_bytes().eq_ignore_ascii_case(text)
+    } else {
+      keyword.as_bytes() == text
+    };
+
+    if base == token_type && equal {
+      return keyword_type;
+    }
   }
+
+  return token_type;
 }
 
 pub struct Lexer<'a>
 {
   bytes: &'a [u8],
   consumed: usize,
+  context: usize,
+  mode: usize,
+  mode_stack: Vec<usize>,
 }
 impl<'a> Lexer<'a>
 {
@@ -27,12 +80,15 @@ impl<'a> Lexer<'a>
     Lexer {
       bytes: text.as_bytes(),
       consumed: 0,
+      context: 2,
+      mode: 0,
+      mode_stack: Vec::new(),
     }
   }
 
-  pub fn tokenize(&mut self) -> Result<Vec<Token>, usize>
+  pub fn tokenize(&mut self) -> Result<Vec<(Token, &'a str)>, usize>
   {
-    let mut tokens: Vec<Token> = Vec::new();
+    let mut tokens: Vec<(Token, &'a str)> = Vec::new();
 
     while let Some(parse) = self.next()
     {
@@ -45,54 +101,129 @@ impl<'a> Lexer<'a>
 
     return Ok(tokens);
   }
+
+  // Gives an error at offset, after which the lexer stops.
+  fn fail(&mut self, offset: usize) -> Option<Result<(Token, &'a str), usize>>
+  {
+    self.bytes = &[];
+    return Some(Err(offset));
+  }
 }
 
 impl<'a> Iterator for Lexer<'a>
 {
-  type Item = Result<Token, usize>;
+  type Item = Result<(Token, &'a str), usize>;
 
-  fn next(&mut self) -> Option<Result<Token, usize>>
+  // Gives every token along with the text it matched, or the byte offset
+  // of an error
+  fn next(&mut self) -> Option<Result<(Token, &'a str), usize>>
   {
-    if self.bytes.len() == 0 {
-      return None;
-    }
+    loop
+    {
+      if self.bytes.len() == 0 {
+        return None;
+      }
 
-    let mut state: usize = 0;
-    let mut marker: usize = 0;
-    let mut best_match: Option<(usize, isize)> = None;
+      // Lines and the text have start states of their own, see Dfa in src/dfa.rs
 
-    if ANSWERS[state] != -1
-    {
-      best_match = Some((marker, ANSWERS[state]));
-    }
+      let mut state: usize = STARTS[self.mode][self.context];
+      let mut marker: usize = 0;
+      let mut best_match: Option<(usize, isize)> = None;
 
-    while marker < self.bytes.len()
-    {
-      let next_state = TRANSITIONS[state][self.bytes[marker] as usize];
+      // Where each trailing context token was last marked, see DfaState in src/dfa.rs
 
-      if next_state == -1 {
-        break;
+      let mut marked = [0; $token-count$];
+
+      for &token in MARKS[state].iter() {
+        marked[token] = marker;
       }
 
-      state = next_state as usize;
-      marker += 1;
+      let answer = accepts(state, self.bytes);
 
-      if ANSWERS[state] != -1
+      if answer != -1
       {
-        best_match = Some((marker, ANSWERS[state]));
+        let end = if TRAILING[answer as usize] { marked[answer as usize] } else { marker };
+        best_match = Some((end, answer));
       }
-    }
 
-    if let Some((marker, token_type)) = best_match
-    {
-      let text = from_utf8(&self.bytes[..marker]).unwrap();
-      let token = Token::parse(token_type, text);
+      while marker < self.bytes.len()
+      {
+        let next_state = transition(state, self.bytes[marker]);
+
+        if next_state == -1 {
+          break;
+        }
+
+        state = next_state as usize;
+        marker += 1;
+
+        for &token in MARKS[state].iter() {
+          marked[token] = marker;
+        }
+
+        let answer = accepts(state, &self.bytes[marker..]);
+
+        // A rejected state is an error unless something longer matches
+
+        if answer == -2
+        {
+          best_match = best_match.map(|(end, _)| (end, -2));
+        }
+        else if answer != -1
+        {
+          let end = if TRAILING[answer as usize] { marked[answer as usize] } else { marker };
+          best_match = Some((end, answer));
+        }
+      }
+
+      // The error is reported at the char after the token
+
+      if let Some((end, -2)) = best_match {
+        return self.fail(self.consumed + end);
+      }
+
+      if let Some((marker, token_type)) = best_match
+      {
+        let token_type = find_keyword(token_type, &self.bytes[..marker]);
+
+        match ACTIONS[token_type as usize]
+        {
+          Action::None => {},
+          Action::Switch(mode) => self.mode = mode,
+          Action::Push(mode) =>
+          {
+            self.mode_stack.push(self.mode);
+            self.mode = mode;
+          },
+          Action::Pop =>
+          {
+            match self.mode_stack.pop()
+            {
+              Some(mode) => self.mode = mode,
+              None => return self.fail(self.consumed),
+            }
+          },
+        }
+
+        let bytes: &'a [u8] = self.bytes;
+        let text = from_utf8(&bytes[..marker]).unwrap();
+
+        self.context = if self.bytes[marker - 1] == b'\n' { 1 } else { 0 };
+        self.consumed += marker;
+        self.bytes = &self.bytes[marker..];
+
+        // Skipped tokens are consumed without being returned
+
+        if SKIP[token_type as usize] {
+          continue;
+        }
+
+        return Some(Ok((Token::parse(token_type), text)));
+      }
 
-      self.consumed += marker;
-      self.bytes = &self.bytes[marker..];
+      // Nothing matches, so the error is reported at the token's start
 
-      return Some(Ok(token));
+      return self.fail(self.consumed);
     }
-    return Some(Err(self.consumed + marker - 1));
   }
 }
@@ -0,0 +1,28 @@
+unsigned char $prefix$lexer_classes[256] = { $c-class-table$ };
+$c-base-type$ $prefix$lexer_base[] = { $c-base-table$ };
+$c-state-type$ $prefix$lexer_default[] = { $c-default-table$ };
+$c-state-type$ $prefix$lexer_next[] = { $c-next-table$ };
+$c-state-type$ $prefix$lexer_check[] = { $c-check-table$ };
+
+/*
+ * Gives the state a byte leads to from state i, or -1 if there isn't one.
+ * The transitions of every state are packed into one array, each marked
+ * with the state it belongs to. A state that doesn't have a transition
+ * there acts like its default state.
+ */
+static long $prefix$lexer_next_state(long i, unsigned char byte)
+{
+  unsigned char byte_class = $prefix$lexer_classes[byte];
+
+  while (i != $c-state-max$)
+  {
+    unsigned long index = $prefix$lexer_base[i] + byte_class;
+
+    if ($prefix$lexer_check[index] == i)
+      return $prefix$lexer_next[index] == $c-state-max$ ? -1 : $prefix$lexer_next[index];
+
+    i = $prefix$lexer_default[i];
+  }
+
+  return -1;
+}
@@ -0,0 +1,32 @@
+const CLASSES: [u8; 256] = [ $class-table$ ];
+const BASE: [$base-type$; $state-table-length$] = [ $base-table$ ];
+const DEFAULT: [$state-type$; $state-table-length$] = [ $default-table$ ];
+const NEXT: [$state-type$; $comb-length$] = [ $next-table$ ];
+const CHECK: [$state-type$; $comb-length$] = [ $check-table$ ];
+
+// Gives the state a byte leads to, or -1 if there isn't one. The
+// transitions of every state are packed into one array, each marked with
+// the state it belongs to. A state that doesn't have a transition there
+// acts like its default state.
+fn transition(state: usize, byte: u8) -> isize
+{
+  let class = CLASSES[byte as usize] as usize;
+  let mut state = state as $state-type$;
+
+  while state != $state-type$::MAX
+  {
+    let index = BASE[state as usize] as usize + class;
+
+    if CHECK[index] == state {
+      if NEXT[index] == $state-type$::MAX {
+        return -1;
+      }
+
+      return NEXT[index] as isize;
+    }
+
+    state = DEFAULT[state as usize];
+  }
+
+  return -1;
+}
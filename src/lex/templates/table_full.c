@@ -0,0 +1,13 @@
+unsigned char $prefix$lexer_classes[256] = { $c-class-table$ };
+$c-state-type$ $prefix$lexer_table[][$c-class-count$] = {
+$c-state-table$};
+
+/*
+ * Gives the state a byte leads to from state i, or -1 if there isn't one.
+ */
+static long $prefix$lexer_next_state(long i, unsigned char byte)
+{
+  $c-state-type$ next = $prefix$lexer_table[i][$prefix$lexer_classes[byte]];
+
+  return next == $c-state-max$ ? -1 : next;
+}
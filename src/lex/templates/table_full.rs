@@ -0,0 +1,15 @@
+const CLASSES: [u8; 256] = [ $class-table$ ];
+const TRANSITIONS: [[$state-type$; $class-count$]; $state-table-length$] = [
+$state-table$];
+
+// Gives the state a byte leads to, or -1 if there isn't one.
+fn transition(state: usize, byte: u8) -> isize
+{
+  let next = TRANSITIONS[state][CLASSES[byte as usize] as usize];
+
+  if next == $state-type$::MAX {
+    return -1;
+  }
+
+  return next as isize;
+}
@@ -1,144 +1,123 @@
 #![feature(unicode)]
 
-use std::io::{self, BufReader, BufRead, Write};
+use std::io::{self, Read, Write};
 use std::fs::File;
+use std::path::Path;
 use std::env;
 
 extern crate getopts;
 extern crate regex_syntax;
 use getopts::Options;
-use regex_syntax::*;
+#[cfg(test)]
+use regex_syntax::Expr;
 
 mod ndfa;
 mod dfa;
 mod bdfa;
 mod lex;
+mod spec;
+mod diagnostic;
+mod error;
+mod range;
+mod minimize;
 
-fn valid_identifier(text: &str) -> bool
-{
-  let mut read_first = false;
+// Exit codes
 
-  for c in text.chars()
-  {
-    if !read_first && !c.is_alphabetic()
-    {
-      return false;
-    }
-    else if !c.is_alphabetic() && !c.is_numeric()
-    {
-      return false;
-    }
+const EXIT_SPEC: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_IO: i32 = 3;
 
-    read_first = true;
-  }
-  return true;
-}
-
-fn execute(input: Option<String>, output: Option<String>, prefix: Option<String>, target: lex::Target) -> i32
+fn execute(input: Option<String>, output: Option<String>, prefix: Option<String>, target: lex::Target, priority: lex::Priority, layout: lex::Layout, verbose: bool) -> i32
 {
-  let mut tokens = Vec::new();
-  let mut number = 1;
-
   let stdin = io::stdin();
   let stdout = io::stdout();
 
-  // Do some awfulness to get the file lines
+  // Read the whole file
+
+  let mut text = String::new();
 
-  let lines = match input
+  let read = match input
   {
-    Some(file) =>
+    Some(ref file) =>
     {
       match File::open(&file)
       {
-        Ok(f) => BufReader::new(f),
+        Ok(mut f) => f.read_to_string(&mut text),
         Err(_) =>
         {
-          println!("error opening file {}.", file);
-          return 1;
+          eprintln!("error: couldn't open file {}.", file);
+          return EXIT_IO;
         }
-      }.lines().filter(|x| x.is_ok()).map(|x| x.unwrap()).collect::<Vec<String>>()
-    }
-    None =>
-    {
-      stdin.lock().lines().filter(|x| x.is_ok()).map(|x| x.unwrap()).collect::<Vec<String>>()
+      }
     }
+    None => stdin.lock().read_to_string(&mut text),
   };
 
-  // Parse the rolex file
-
-  for line in lines
+  if read.is_err()
   {
-    let split_pos = match line.find(":")
-    {
-      Some(x) => x,
-      None =>
-      {
-        println!("error on line {}: invalid token declaration.", number);
-        return 1;
-      }
-    };
-
-    let (identifier, pattern) = line.split_at(split_pos);
-
-    // Handle the identifier
-
-    let identifier = identifier.trim();
-
-    if !valid_identifier(identifier)
-    {
-      println!("error on line {}: invalid token declaration. bad identifier.", number);
-      return 1;
-    }
-
-    // Handle the regex
+    eprintln!("error: couldn't read input.");
+    return EXIT_IO;
+  }
 
-    let pattern = pattern.split_at(1).1.trim();
+  // Parse the rolex file, printing every error and warning
 
-    let regex = match Expr::parse(pattern)
-    {
-      Ok(x) => x,
-      Err(e) =>
-      {
-        println!("error on line {}: invalid token declaration. bad pattern.", e.to_string());
-        return 1;
-      }
-    };
+  let mut diagnostics = diagnostic::Diagnostics::new();
+  let spec = spec::parse(&text, input.as_ref().map(|x| Path::new(x)), &mut diagnostics);
 
-    tokens.push((String::from(identifier), regex));
-    number += 1;
+  for x in diagnostics.list.iter()
+  {
+    eprintln!("{}", x);
   }
 
+  let spec = match spec
+  {
+    Some(x) => x,
+    None => return EXIT_SPEC,
+  };
+
   let prefix = match prefix
   {
     Some(p) => p,
     None => String::from("")
   };
 
-  let lex_source = lex::output_lex(tokens, prefix, target);
+  let lex_source = match lex::output_lex(&spec, prefix, target, priority, layout, verbose)
+  {
+    Ok(x) => x,
+    Err(e) =>
+    {
+      eprintln!("error: {}", e);
+      return EXIT_SPEC;
+    }
+  };
 
   // Print the output
 
-  match output
+  let written = match output
   {
-    Some(file) =>
+    Some(ref file) =>
     {
       match File::create(&file)
       {
-        Ok(mut f) =>
-        {
-          f.write_all(&lex_source.as_bytes()).unwrap();
-        },
+        Ok(mut f) => f.write_all(&lex_source.as_bytes()),
         Err(_) =>
         {
-          println!("error opening file {}.", file);
-          return 1;
+          eprintln!("error: couldn't open file {}.", file);
+          return EXIT_IO;
         }
       }
     },
     None =>
     {
-      stdout.lock().write_all(&lex_source.as_bytes()).unwrap();
+      let mut out = stdout.lock();
+      out.write_all(&lex_source.as_bytes()).and_then(|_| out.flush())
     }
+  };
+
+  if written.is_err()
+  {
+    eprintln!("error: couldn't write output.");
+    return EXIT_IO;
   }
 
   return 0;
@@ -155,6 +134,9 @@ fn main()
   opts.optopt("o", "", "specify an output filename, defaults to stdout.", "NAME");
   opts.optopt("t", "target", "specify target type. defaults to c.", "TYPE");
   opts.optopt("p", "prefix", "specify the generated parser prefix.", "PREFIX");
+  opts.optopt("", "priority", "specify which token wins when several match, first or last declared. defaults to first.", "ORDER");
+  opts.optopt("", "table", "specify how the C and Rust state tables are laid out, full or comb. defaults to full.", "LAYOUT");
+  opts.optflag("v", "verbose", "print how many states the lexer has before and after minimization.");
   opts.optflag("h", "help", "print this help menu.");
 
   let matches = match opts.parse(&args[1..])
@@ -162,8 +144,8 @@ fn main()
     Ok(m) => m,
     Err(f) =>
     {
-      println!("error: invalid command line arguments. {}", f.to_string());
-      std::process::exit(1);
+      eprintln!("error: invalid command line arguments. {}", f.to_string());
+      std::process::exit(EXIT_USAGE);
     }
   };
 
@@ -202,8 +184,8 @@ fn main()
         Some(t) => t,
         None =>
         {
-          println!("error: invalid target {}", text);
-          std::process::exit(1);
+          eprintln!("error: invalid target {}", text);
+          std::process::exit(EXIT_USAGE);
         }
       }
     },
@@ -229,16 +211,67 @@ fn main()
 
   let prefix = matches.opt_str("p");
 
-  std::process::exit(execute(input, output, prefix, target));
+  let priority = match matches.opt_str("priority")
+  {
+    Some(text) =>
+    {
+      match lex::Priority::parse(&text)
+      {
+        Some(p) => p,
+        None =>
+        {
+          eprintln!("error: invalid priority {}", text);
+          std::process::exit(EXIT_USAGE);
+        }
+      }
+    },
+    None => lex::Priority::First
+  };
+
+  let layout = match matches.opt_str("table")
+  {
+    Some(text) =>
+    {
+      match lex::Layout::parse(&text)
+      {
+        Some(l) => l,
+        None =>
+        {
+          eprintln!("error: invalid table layout {}", text);
+          std::process::exit(EXIT_USAGE);
+        }
+      }
+    },
+    None => lex::Layout::Full
+  };
+
+  let verbose = matches.opt_present("v");
+
+  std::process::exit(execute(input, output, prefix, target, priority, layout, verbose));
+}
+
+/*
+ * Parses a spec for a test, giving back the diagnostics if it fails.
+ */
+#[cfg(test)]
+fn parse_spec(text: &str, file: Option<&Path>) -> Result<spec::Spec, Vec<diagnostic::Diagnostic>>
+{
+  let mut diagnostics = diagnostic::Diagnostics::new();
+
+  match spec::parse(text, file, &mut diagnostics)
+  {
+    Some(x) => Ok(x),
+    None => Err(diagnostics.list),
+  }
 }
 
 #[test]
 fn regex_test()
 {
   let regex = Expr::parse(r"-?[0-9]+(\.[0-9]*)?").unwrap();
-  let ndfa = ndfa::Ndfa::from_regex(&regex);
-  let dfa = dfa::Dfa::from_ndfa(&ndfa);
-  let bdfa = bdfa::Bdfa::from_dfa(&dfa);
+  let ndfa = ndfa::Ndfa::from_regex(&regex).unwrap();
+  let dfa = dfa::Dfa::from_ndfa(&ndfa).unwrap();
+  let bdfa = bdfa::Bdfa::from_dfa(&dfa).unwrap();
 
   assert!(!bdfa.accepts("").is_some());
   assert!(!bdfa.accepts("hello").is_some());
@@ -256,9 +289,9 @@ fn regex_test()
 fn emoji_test()
 {
   let regex = Expr::parse(r"(😎|🙁)+").unwrap();
-  let ndfa = ndfa::Ndfa::from_regex(&regex);
-  let dfa = dfa::Dfa::from_ndfa(&ndfa);
-  let bdfa = bdfa::Bdfa::from_dfa(&dfa);
+  let ndfa = ndfa::Ndfa::from_regex(&regex).unwrap();
+  let dfa = dfa::Dfa::from_ndfa(&ndfa).unwrap();
+  let bdfa = bdfa::Bdfa::from_dfa(&dfa).unwrap();
 
   assert!(!bdfa.accepts("").is_some());
   assert!(!bdfa.accepts("😤").is_some());
@@ -273,6 +306,803 @@ fn emoji_test()
   assert!(bdfa.accepts("😎😎🙁").is_some());
 }
 
+#[test]
+fn any_char_test()
+{
+  let regex = Expr::parse(r"(?s)<.>").unwrap();
+  let ndfa = ndfa::Ndfa::from_regex(&regex).unwrap();
+  let dfa = dfa::Dfa::from_ndfa(&ndfa).unwrap();
+  let bdfa = bdfa::Bdfa::from_dfa(&dfa).unwrap();
+
+  assert!(!bdfa.accepts("<>").is_some());
+  assert!(!bdfa.accepts("<ab>").is_some());
+
+  assert!(bdfa.accepts("<a>").is_some());
+  assert!(bdfa.accepts("<\n>").is_some());
+  assert!(bdfa.accepts("<\0>").is_some());
+  assert!(bdfa.accepts("<😎>").is_some());
+  assert!(bdfa.accepts("<\u{10FFFF}>").is_some());
+}
+
+#[test]
+fn any_char_no_newline_test()
+{
+  let regex = Expr::parse(r"//.*").unwrap();
+  let ndfa = ndfa::Ndfa::from_regex(&regex).unwrap();
+  let dfa = dfa::Dfa::from_ndfa(&ndfa).unwrap();
+  let bdfa = bdfa::Bdfa::from_dfa(&dfa).unwrap();
+
+  assert!(!bdfa.accepts("/").is_some());
+  assert!(!bdfa.accepts("// a\nb").is_some());
+
+  assert!(bdfa.accepts("//").is_some());
+  assert!(bdfa.accepts("// a comment 😎").is_some());
+  assert!(bdfa.accepts("//\r\t\u{10FFFF}").is_some());
+}
+
+#[test]
+fn byte_class_test()
+{
+  let regex = Expr::parse(r"(?-u)[a-c\x00]+").unwrap();
+  let ndfa = ndfa::Ndfa::from_regex(&regex).unwrap();
+  let dfa = dfa::Dfa::from_ndfa(&ndfa).unwrap();
+  let bdfa = bdfa::Bdfa::from_dfa(&dfa).unwrap();
+
+  assert!(!bdfa.accepts("").is_some());
+  assert!(!bdfa.accepts("abd").is_some());
+
+  assert!(bdfa.accepts("a").is_some());
+  assert!(bdfa.accepts("cab\0").is_some());
+
+  let regex = Expr::parse(r"(?-u)[^a]").unwrap();
+
+  assert!(ndfa::Ndfa::from_regex(&regex).is_err());
+}
+
+#[test]
+fn byte_literal_test()
+{
+  let regex = Expr::parse(r"(?-u)if|\x41").unwrap();
+  let ndfa = ndfa::Ndfa::from_regex(&regex).unwrap();
+  let dfa = dfa::Dfa::from_ndfa(&ndfa).unwrap();
+  let bdfa = bdfa::Bdfa::from_dfa(&dfa).unwrap();
+
+  assert!(!bdfa.accepts("i").is_some());
+  assert!(!bdfa.accepts("IF").is_some());
+
+  assert!(bdfa.accepts("if").is_some());
+  assert!(bdfa.accepts("A").is_some());
+}
+
+#[test]
+fn case_insensitive_test()
+{
+  let regex = Expr::parse(r"(?i)select|(?i-u)k").unwrap();
+  let ndfa = ndfa::Ndfa::from_regex(&regex).unwrap();
+  let dfa = dfa::Dfa::from_ndfa(&ndfa).unwrap();
+  let bdfa = bdfa::Bdfa::from_dfa(&dfa).unwrap();
+
+  assert!(!bdfa.accepts("selec").is_some());
+  assert!(!bdfa.accepts("\u{212A}").is_some());
+
+  assert!(bdfa.accepts("select").is_some());
+  assert!(bdfa.accepts("SELECT").is_some());
+  assert!(bdfa.accepts("sElEcT").is_some());
+  assert!(bdfa.accepts("K").is_some());
+
+  // Unicode literals fold the way classes do, like `k` and the Kelvin sign
+
+  let regex = Expr::parse(r"(?i)ök").unwrap();
+  let dfa = dfa::Dfa::from_ndfa(&ndfa::Ndfa::from_regex(&regex).unwrap()).unwrap();
+
+  assert!(dfa.accepts("ÖK").is_some());
+  assert!(dfa.accepts("ö\u{212A}").is_some());
+}
+
+#[test]
+fn anchor_test()
+{
+  let regex = Expr::parse(r"(?m)^#[a-z]+|(?m)[a-z]+$|\A=|\d\z").unwrap();
+  let ndfa = ndfa::Ndfa::from_regex(&regex).unwrap();
+  let dfa = dfa::Dfa::from_ndfa(&ndfa).unwrap().minimize();
+  let bdfa = bdfa::Bdfa::from_dfa(&dfa).unwrap().minimize();
+
+  assert!(bdfa.accepts("#if").is_some());
+  assert!(bdfa.accepts("abc").is_some());
+  assert!(bdfa.accepts("=").is_some());
+  assert!(bdfa.accepts("4").is_some());
+
+  // Starting in the middle of a line, or ending before anything but a
+  // newline, only the unanchored parts match
+
+  let start = |context: usize| &bdfa.states[bdfa.starts[0][context] as usize];
+  let step = |state: &bdfa::BdfaState, byte: u8| &bdfa.states[state.next[bdfa.classes[byte as usize]] as usize];
+
+  assert_eq!(start(0).next[bdfa.classes[b'#' as usize]], -1);
+  assert_eq!(start(1).next[bdfa.classes[b'=' as usize]], -1);
+  assert!(start(2).next[bdfa.classes[b'=' as usize]] != -1);
+
+  let word = step(start(0), b'a');
+
+  assert_eq!(word.answer, None);
+  assert!(word.line_end_answer.is_some());
+
+  let digit = step(start(0), b'4');
+
+  assert_eq!(digit.line_end_answer, None);
+  assert!(digit.text_end_answer.is_some());
+
+  let error = ndfa::Ndfa::from_regex(&Expr::parse(r"a$b").unwrap()).err().unwrap();
+  assert!(error.message.contains("end of a token"));
+}
+
+#[test]
+fn spec_anchor_test()
+{
+  let text = vec![
+    r"start: ^\#[a-z]+",
+    r"first: \A=",
+    r"last: [0-9]\z",
+    r"word: [a-z]+",
+    r"other: [\n#=0-9]",
+  ].join("\n");
+
+  let spec = parse_spec(&text, None).ok().unwrap();
+
+  assert_eq!(spec.tokens[0].regex, Expr::parse(r"(?m)^#[a-z]+").unwrap());
+  assert_eq!(spec.tokens[1].regex, Expr::parse(r"\A=").unwrap());
+
+  let rules = spec.tokens.iter().enumerate().map(|(i, x)| ndfa::Rule
+  {
+    name: &x.name,
+    regex: &x.regex,
+    trailing: None,
+    not_followed_by: None,
+    answer: i,
+    mode: 0,
+    rank: i,
+  }).collect();
+  let dfa = dfa::Dfa::from_ndfa(&ndfa::Ndfa::from_regexes(rules, 1).unwrap()).unwrap().minimize();
+
+  // Lex the way the generated lexers do, taking the longest match with
+  // the start state and answers that fit where it is
+
+  let lex = |text: &str|
+  {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+
+    while index < chars.len()
+    {
+      let context = if index == 0 { 2 } else if chars[index - 1] == '\n' { 1 } else { 0 };
+      let mut state = dfa.starts[0][context];
+      let mut best = None;
+
+      for end in index..chars.len()
+      {
+        state = match dfa.states[state].get(chars[end])
+        {
+          Some(x) => x,
+          None => break,
+        };
+
+        let answer = match chars.get(end + 1)
+        {
+          None => dfa.states[state].text_end_answer,
+          Some(&'\n') => dfa.states[state].line_end_answer,
+          Some(_) => dfa.states[state].answer,
+        };
+
+        if let Some(answer) = answer
+        {
+          best = Some((answer, end + 1));
+        }
+      }
+
+      let (answer, end) = best.unwrap();
+      tokens.push(answer);
+      index = end;
+    }
+
+    return tokens;
+  };
+
+  assert_eq!(lex("#a\n#b"), vec![0, 4, 0]);
+  assert_eq!(lex("=#a"), vec![1, 4, 3]);
+  assert_eq!(lex("a=1\n2"), vec![3, 4, 4, 4, 2]);
+}
+
+#[test]
+fn class_range_test()
+{
+  let regex = Expr::parse(r#""[^"]*"|[\x{D000}-\x{F000}]"#).unwrap();
+  let ndfa = ndfa::Ndfa::from_regex(&regex).unwrap();
+  let dfa = dfa::Dfa::from_ndfa(&ndfa).unwrap();
+
+  assert!(dfa.states.len() < 10);
+
+  assert!(!dfa.accepts("\"").is_some());
+  assert!(!dfa.accepts("\"a\"b").is_some());
+
+  assert!(dfa.accepts("\"\"").is_some());
+  assert!(dfa.accepts("\"hello, 😎\"").is_some());
+  assert!(dfa.accepts("\u{D7FF}").is_some());
+  assert!(dfa.accepts("\u{E000}").is_some());
+
+  let bdfa = bdfa::Bdfa::from_dfa(&dfa).unwrap();
+
+  assert!(bdfa.states.len() < 30);
+
+  for text in ["\"", "\"a\"b", "\"\"", "\"hello, 😎\"", "\u{CFFF}", "\u{D7FF}", "\u{E000}", "\u{F001}"].iter()
+  {
+    assert_eq!(bdfa.accepts(text), dfa.accepts(text));
+  }
+}
+
+#[test]
+fn spec_comment_test()
+{
+  let text = vec![
+    "# a full line comment",
+    "",
+    "/// A number.",
+    "number: [0-9]+ # a trailing comment",
+    "hash: [ #]\\#",
+    "open: [[] # a bracket in a class",
+    "close (not-followed-by = [],]): []#]+ # a bracket starting a class",
+  ].join("\n");
+
+  let spec = parse_spec(&text, None).ok().unwrap();
+
+  assert_eq!(spec.tokens.len(), 4);
+  assert_eq!(spec.tokens[0].doc, vec![String::from("A number.")]);
+  assert!(spec.tokens[1].doc.is_empty());
+  assert_eq!(spec.tokens[1].regex, Expr::parse(r"[ #]\#").unwrap());
+  assert_eq!(spec.tokens[2].regex, Expr::parse(r"[\[]").unwrap());
+  assert_eq!(spec.tokens[3].regex, Expr::parse(r"[\]#]+").unwrap());
+  assert_eq!(spec.tokens[3].not_followed_by, Some(Expr::parse(r"[\],]").unwrap()));
+}
+
+#[test]
+fn spec_definition_test()
+{
+  let text = vec![
+    "number: {digit}+",
+    "digit = [0-9]",
+    "pair: {digit}{2}",
+    "bracket: [[]{digit}[]{]",
+  ].join("\n");
+
+  let spec = parse_spec(&text, None).ok().unwrap();
+
+  assert_eq!(spec.tokens.len(), 3);
+  assert_eq!(spec.tokens[0].regex, Expr::parse(r"(?:[0-9])+").unwrap());
+  assert_eq!(spec.tokens[1].regex, Expr::parse(r"(?:[0-9]){2}").unwrap());
+  assert_eq!(spec.tokens[2].regex, Expr::parse(r"[\[](?:[0-9])[\]{]").unwrap());
+
+  let text = vec![
+    "a = x{b}",
+    "b = {a}y",
+    "token: {a}",
+  ].join("\n");
+
+  assert_eq!(parse_spec(&text, None).err().unwrap()[0].location.as_ref().unwrap().line, 1);
+}
+
+#[test]
+fn spec_attribute_test()
+{
+  let text = vec![
+    "number: [0-9]+",
+    "whitespace (skip): [ \\t]+",
+  ].join("\n");
+
+  let spec = parse_spec(&text, None).ok().unwrap();
+
+  assert!(!spec.tokens[0].skip);
+  assert!(spec.tokens[1].skip);
+
+  let text = vec![
+    "number (bogus): [0-9]+",
+  ].join("\n");
+
+  assert!(parse_spec(&text, None).is_err());
+}
+
+#[test]
+fn spec_mode_test()
+{
+  let text = vec![
+    "quote (push = string): \"",
+    "mode string {",
+    "  text: [a-z]+",
+    "  end (pop): \"",
+    "}",
+  ].join("\n");
+
+  let spec = parse_spec(&text, None).ok().unwrap();
+
+  assert_eq!(spec.modes, vec![String::from("default"), String::from("string")]);
+  assert_eq!(spec.tokens[0].mode, 0);
+  assert_eq!(spec.tokens[0].action, spec::Action::Push(1));
+  assert_eq!(spec.tokens[1].mode, 1);
+  assert_eq!(spec.tokens[2].action, spec::Action::Pop);
+}
+
+#[test]
+fn rust_lexer_test()
+{
+  let text = vec![
+    "letter = [a-z]",
+    "heading: ^\\#{letter}+",
+    "float: [0-9]+\\./[0-9]",
+    "number (not-followed-by = {letter}): [0-9]+",
+    "ident: {letter}+",
+    "ws (skip): [ \\n]+",
+    "quote (push = string): \"",
+    "keywords ident {",
+    "  if",
+    "  do",
+    "  crate",
+    "  self",
+    "  super",
+    "}",
+    "mode string {",
+    "  text: [^\"]+",
+    "  end (pop): \"",
+    "}",
+  ].join("\n");
+
+  let spec = parse_spec(&text, None).ok().unwrap();
+  let source = lex::output_lex(&spec, String::new(), lex::Target::Rust, lex::Priority::First, lex::Layout::Comb, false).ok().unwrap();
+
+  // Build the lexer with a main that prints every token it gives
+
+  let dir = std::env::temp_dir().join(format!("rolex_rust_lexer_test_{}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+
+  File::create(dir.join("lex.rs")).unwrap().write_all(source.as_bytes()).unwrap();
+  File::create(dir.join("main.rs")).unwrap().write_all(b"#![allow(warnings)]\nmod lex;\nfn main()\n{\n  for x in lex::Lexer::new(&std::env::args().nth(1).unwrap())\n  {\n    println!(\"{:?}\", x);\n  }\n}\n").unwrap();
+
+  let built = std::process::Command::new("rustc")
+    .arg("-o").arg(dir.join("lexer"))
+    .arg(dir.join("main.rs"))
+    .status().unwrap();
+
+  assert!(built.success());
+
+  let run = |input: &str| String::from_utf8(std::process::Command::new(dir.join("lexer")).arg(input).output().unwrap().stdout).unwrap();
+
+  let output = run("#a 1.5 if do crate self super \"x y\"\n#b 12c");
+  let bad_start = run("@ab");
+  let bad_later = run("ab @");
+
+  std::fs::remove_dir_all(&dir).unwrap();
+
+  assert_eq!(output.lines().collect::<Vec<_>>(), vec![
+    "Ok((heading, \"#a\"))",
+    "Ok((float, \"1.\"))",
+    "Ok((number, \"5\"))",
+    "Ok((if, \"if\"))",
+    "Ok((do, \"do\"))",
+    "Ok((crate_, \"crate\"))",
+    "Ok((self_, \"self\"))",
+    "Ok((super_, \"super\"))",
+    "Ok((quote, \"\\\"\"))",
+    "Ok((text, \"x y\"))",
+    "Ok((end, \"\\\"\"))",
+    "Ok((heading, \"#b\"))",
+    "Err(41)",
+  ]);
+
+  // Chars no token can start with are reported where they are
+
+  assert_eq!(bad_start.lines().collect::<Vec<_>>(), vec!["Err(0)"]);
+  assert_eq!(bad_later.lines().collect::<Vec<_>>(), vec!["Ok((ident, \"ab\"))", "Err(3)"]);
+}
+
+#[test]
+fn priority_test()
+{
+  let keyword = Expr::parse(r"if").unwrap();
+  let identifier = Expr::parse(r"[a-z]+").unwrap();
+
+  let rules = vec![
+    ndfa::Rule { name: "keyword", regex: &keyword, trailing: None, not_followed_by: None, answer: 0, mode: 0, rank: 0 },
+    ndfa::Rule { name: "identifier", regex: &identifier, trailing: None, not_followed_by: None, answer: 1, mode: 0, rank: 1 },
+  ];
+  let dfa = dfa::Dfa::from_ndfa(&ndfa::Ndfa::from_regexes(rules, 1).unwrap()).unwrap();
+
+  assert_eq!(dfa.accepts("if"), Some(0));
+  assert_eq!(dfa.accepts("iff"), Some(1));
+
+  let rules = vec![
+    ndfa::Rule { name: "keyword", regex: &keyword, trailing: None, not_followed_by: None, answer: 0, mode: 0, rank: 1 },
+    ndfa::Rule { name: "identifier", regex: &identifier, trailing: None, not_followed_by: None, answer: 1, mode: 0, rank: 0 },
+  ];
+  let dfa = dfa::Dfa::from_ndfa(&ndfa::Ndfa::from_regexes(rules, 1).unwrap()).unwrap();
+
+  assert_eq!(dfa.accepts("if"), Some(1));
+
+  // Any priority that parses can be ranked
+
+  let text = vec![
+    "a (priority = 9223372036854775807): a",
+    "b (priority = -9223372036854775808): b",
+  ].join("\n");
+
+  let spec = parse_spec(&text, None).ok().unwrap();
+  assert!(lex::output_lex(&spec, String::new(), lex::Target::C, lex::Priority::First, lex::Layout::Full, false).is_ok());
+}
+
+#[test]
+fn not_followed_by_test()
+{
+  let text = vec![
+    "letter = [a-z]",
+    "keyword: \"in\"",
+    "number (not-followed-by = {letter}, nocase): [0-9]+",
+    "hex: 0x[0-9a-f]+",
+    "identifier (not-followed-by = [0-9]): {letter}+",
+  ].join("\n");
+
+  let spec = parse_spec(&text, None).ok().unwrap();
+
+  assert_eq!(spec.tokens[1].not_followed_by, Some(Expr::parse(r"(?i)[a-z]").unwrap()));
+
+  let rules = spec.tokens.iter().enumerate().map(|(i, x)| ndfa::Rule
+  {
+    name: &x.name,
+    regex: &x.regex,
+    trailing: None,
+    not_followed_by: x.not_followed_by.as_ref(),
+    answer: i,
+    mode: 0,
+    rank: i,
+  }).collect();
+  let dfa = dfa::Dfa::from_ndfa(&ndfa::Ndfa::from_regexes(rules, 1).unwrap()).unwrap().minimize();
+
+  let state = |text: &str| text.chars().try_fold(dfa.starts[0][0], |state, x| dfa.states[state].get(x)).map(|x| &dfa.states[x]);
+
+  assert!(state("12A").unwrap().rejected);
+  assert!(state("0x").unwrap().rejected);
+  assert_eq!(state("0x1").unwrap().answer, Some(2));
+
+  // Only the token that's accepted is restricted
+
+  assert!(state("ab1").unwrap().rejected);
+  assert!(state("in1").is_none());
+}
+
+#[test]
+fn repetition_test()
+{
+  let regex = Expr::parse(r"x{2,1000}").unwrap();
+  let ndfa = ndfa::Ndfa::from_regex(&regex).unwrap();
+  let dfa = dfa::Dfa::from_ndfa(&ndfa).unwrap();
+
+  assert!(ndfa.states.len() < 5000);
+
+  assert!(!dfa.accepts("x").is_some());
+  assert!(!dfa.accepts(&"x".repeat(1001)).is_some());
+
+  assert!(dfa.accepts("xx").is_some());
+  assert!(dfa.accepts(&"x".repeat(1000)).is_some());
+
+  let regex = Expr::parse(r"[0-9a-f]{8}(-[0-9a-f]{4}){3}").unwrap();
+  let dfa = dfa::Dfa::from_ndfa(&ndfa::Ndfa::from_regex(&regex).unwrap()).unwrap();
+
+  assert!(dfa.accepts("0123abcd-0000-ffff-1234").is_some());
+  assert!(!dfa.accepts("0123abcd-0000-ffff").is_some());
+
+  let regex = Expr::parse(r"x{1001}").unwrap();
+  let error = ndfa::Ndfa::from_regex(&regex).err().unwrap();
+
+  assert!(error.message.contains("1000"));
+
+  let regex = Expr::parse(r"(x{1000}){1000}|y").unwrap();
+
+  assert!(ndfa::Ndfa::from_regex(&regex).is_err());
+}
+
+#[test]
+fn minimize_test()
+{
+  let first = Expr::parse(r"x(a|b)+").unwrap();
+  let second = Expr::parse(r"y(é|c)+").unwrap();
+
+  let rules = vec![
+    ndfa::Rule { name: "first", regex: &first, trailing: None, not_followed_by: None, answer: 0, mode: 0, rank: 0 },
+    ndfa::Rule { name: "second", regex: &second, trailing: None, not_followed_by: None, answer: 1, mode: 0, rank: 1 },
+  ];
+  let dfa = dfa::Dfa::from_ndfa(&ndfa::Ndfa::from_regexes(rules, 1).unwrap()).unwrap().minimize();
+
+  assert_eq!(dfa.states.len(), 5);
+
+  assert_eq!(dfa.accepts("xab"), Some(0));
+  assert_eq!(dfa.accepts("yéc"), Some(1));
+  assert_eq!(dfa.accepts("xa"), Some(0));
+  assert_eq!(dfa.accepts("xc"), None);
+
+  let bdfa = bdfa::Bdfa::from_dfa(&dfa).unwrap().minimize();
+
+  assert_eq!(bdfa.states.len(), 6);
+
+  // x, y, a and b, c, the two bytes of é, and everything else
+
+  assert_eq!(bdfa.class_count, 7);
+
+  assert_eq!(bdfa.accepts("xab"), Some(0));
+  assert_eq!(bdfa.accepts("yéc"), Some(1));
+  assert_eq!(bdfa.accepts("yé"), Some(1));
+  assert_eq!(bdfa.accepts("x\u{C3}"), None);
+}
+
+#[test]
+fn spec_include_test()
+{
+  let dir = std::env::temp_dir().join(format!("rolex_spec_include_test_{}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+
+  File::create(dir.join("numbers.rlx")).unwrap().write_all(b"digit = [0-9]\nint: {digit}+\n").unwrap();
+  File::create(dir.join("cycle.rlx")).unwrap().write_all(b"include \"main.rlx\"\n").unwrap();
+
+  let text = vec![
+    "import \"numbers.rlx\" as num",
+    "pair: {num.digit}{2}",
+  ].join("\n");
+
+  let spec = parse_spec(&text, Some(&dir.join("main.rlx"))).ok().unwrap();
+
+  assert_eq!(spec.tokens[0].name, "num_int");
+  assert_eq!(spec.tokens[1].regex, Expr::parse(r"(?:[0-9]){2}").unwrap());
+
+  let text = vec![
+    "include \"cycle.rlx\"",
+  ].join("\n");
+
+  File::create(dir.join("main.rlx")).unwrap().write_all(text.as_bytes()).unwrap();
+
+  let error = parse_spec(&text, Some(&dir.join("main.rlx"))).err().unwrap();
+
+  std::fs::remove_dir_all(&dir).unwrap();
+
+  assert_eq!(error[0].location.as_ref().unwrap().included_from.len(), 1);
+}
+
+#[test]
+fn spec_keyword_test()
+{
+  let text = vec![
+    "identifier: [a-z]+",
+    "plus: \"+\"  # a literal",
+    "string: \"[^\"]*\"",
+    "keywords identifier {",
+    "  if else",
+    "}",
+  ].join("\n");
+
+  let spec = parse_spec(&text, None).ok().unwrap();
+
+  assert_eq!(spec.tokens[1].regex, Expr::parse(r"\+").unwrap());
+  assert_eq!(spec.tokens[2].regex, Expr::parse(r#""[^"]*""#).unwrap());
+  assert!(spec.tokens[0].keyword.is_none());
+  assert_eq!(spec.tokens[3].name, "if");
+  assert_eq!(spec.tokens[4].keyword.as_ref().unwrap().base, 0);
+  assert_eq!(spec.tokens[4].keyword.as_ref().unwrap().text, "else");
+
+  // Keywords their base can't match, or from another mode, never apply
+
+  let text = vec![
+    "number: [0-9]+",
+    "word: [a-z]+",
+    "keywords number {",
+    "  abc",
+    "}",
+    "mode m {",
+    "  keywords word {",
+    "    if",
+    "  }",
+    "}",
+  ].join("\n");
+
+  let error = parse_spec(&text, None).err().unwrap();
+  assert!(error[0].message.contains("abc can never be matched"));
+  assert!(error[1].message.contains("if is in mode m"));
+}
+
+#[test]
+fn spec_nocase_test()
+{
+  let text = vec![
+    "name = [a-zÖ]+",
+    "identifier: {name}",
+    "string (nocase): x\"[^a]*\"",
+    "keywords identifier (nocase) {",
+    "  select",
+    "}",
+    "keywords identifier {",
+    "  Ölfass",
+    "}",
+  ].join("\n");
+
+  let spec = parse_spec(&text, None).ok().unwrap();
+
+  assert!(!spec.tokens[0].nocase);
+  assert!(spec.tokens[1].nocase);
+  assert!(spec.tokens[2].nocase);
+  assert_eq!(spec.tokens[1].regex, Expr::parse(r#"(?i)x"[^a]*""#).unwrap());
+  assert_eq!(spec.tokens[2].regex, Expr::parse(r"(?i)select").unwrap());
+
+  let error = parse_spec(&text.replace("keywords identifier {", "keywords identifier (nocase) {"), None).err().unwrap();
+  assert!(error[0].message.contains("Ölfass"));
+}
+
+#[test]
+fn spec_trailing_test()
+{
+  let text = vec![
+    r"number: [0-9]+\./[0-9]",
+    r"call: [a-z]+/[ ]*\(",
+    r#"comment: "//""#,
+    r"slash: [/]+",
+    r"escaped: \/\/.*",
+    r"bracket: a[[]/b",
+    r"close: []/]+",
+  ].join("\n");
+
+  let spec = parse_spec(&text, None).ok().unwrap();
+
+  assert_eq!(spec.tokens[0].regex, Expr::parse(r"[0-9]+\.").unwrap());
+  assert_eq!(spec.tokens[0].trailing, Some(Expr::parse(r"[0-9]").unwrap()));
+  assert_eq!(spec.tokens[1].trailing, Some(Expr::parse(r"[ ]*\(").unwrap()));
+  assert_eq!(spec.tokens[2].trailing, None);
+  assert_eq!(spec.tokens[3].trailing, None);
+  assert_eq!(spec.tokens[4].regex, Expr::parse(r"//.*").unwrap());
+  assert_eq!(spec.tokens[4].trailing, None);
+  assert_eq!(spec.tokens[5].trailing, Some(Expr::parse(r"b").unwrap()));
+  assert_eq!(spec.tokens[6].trailing, None);
+
+  let source = lex::output_lex(&spec, String::new(), lex::Target::C, lex::Priority::First, lex::Layout::Full, false).ok().unwrap();
+  assert!(source.contains("lexer_trailing[] = { 1, 1, 0, 0, 0, 1, 0, "));
+
+  let error = parse_spec("a: //.*", None).err().unwrap();
+  assert!(error[0].message.contains("only have one trailing context"));
+  assert!(error[0].message.contains("`\\/`"));
+
+  let error = parse_spec("a: /x", None).err().unwrap();
+  assert!(error[0].message.contains("both sides"));
+
+  let spec = parse_spec("a: a+/ab", None).ok().unwrap();
+  let error = lex::output_lex(&spec, String::new(), lex::Target::C, lex::Priority::First, lex::Layout::Full, false).err().unwrap();
+  assert!(error.message.contains("ambiguous"));
+}
+
+#[test]
+fn spec_syntax_test()
+{
+  let text = vec![
+    "arrow: ` -> `",
+    "number (",
+    "  priority = 2,",
+    "  skip",
+    "): `[0-9]+",
+    "     (\\.[0-9]+)?`",
+    "tick: `\\``",
+  ].join("\n");
+
+  let spec = parse_spec(&text, None).ok().unwrap();
+
+  assert_eq!(spec.tokens[0].regex, Expr::parse(r" -> ").unwrap());
+  assert_eq!(spec.tokens[1].regex, Expr::parse(r"[0-9]+(\.[0-9]+)?").unwrap());
+  assert_eq!(spec.tokens[1].priority, 2);
+  assert!(spec.tokens[1].skip);
+  assert_eq!(spec.tokens[2].regex, Expr::parse(r"`").unwrap());
+
+  let text = vec![
+    "number: [0-9]+",
+    "string: `[a-z]*",
+  ].join("\n");
+
+  assert_eq!(parse_spec(&text, None).err().unwrap()[0].location.as_ref().unwrap().line, 2);
+}
+
+#[test]
+fn spec_diagnostic_test()
+{
+  let text = vec![
+    "digit = [0-9]",
+    "number: {digit}+",
+    "bad: [a-z",
+    "word (bogus): [a-z]+",
+    "unused = x",
+  ].join("\n");
+
+  let errors = parse_spec(&text, None).err().unwrap();
+  let location = |i: usize| errors[i].location.clone().unwrap();
+
+  assert_eq!(errors.len(), 3);
+  assert_eq!((location(0).line, location(0).column), (3, 10));
+  assert_eq!((location(1).line, location(1).column, location(1).length), (4, 7, 5));
+  assert_eq!(errors[2].severity, diagnostic::Severity::Warning);
+  assert_eq!(location(2).snippet, "unused = x");
+}
+
+#[test]
+fn compile_error_test()
+{
+  let anchored = Expr::parse(r"(?m)i^f").unwrap();
+  let rules = vec![
+    ndfa::Rule { name: "keyword", regex: &anchored, trailing: None, not_followed_by: None, answer: 0, mode: 0, rank: 0 },
+  ];
+
+  let error = ndfa::Ndfa::from_regexes(rules, 1).err().unwrap();
+
+  assert_eq!(error.token, Some(String::from("keyword")));
+  assert!(error.message.contains("`^`"));
+  assert!(error.message.contains("start of a token"));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn write_error_test()
+{
+  let dir = std::env::temp_dir().join(format!("rolex_write_error_test_{}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+
+  let input = dir.join("test.rlx");
+  File::create(&input).unwrap().write_all(b"number: [0-9]+").unwrap();
+
+  // A full disk is an I/O error, not a panic
+
+  let status = execute(Some(input.to_string_lossy().into_owned()), Some(String::from("/dev/full")), None, lex::Target::C, lex::Priority::First, lex::Layout::Full, false);
+
+  std::fs::remove_dir_all(&dir).unwrap();
+
+  assert_eq!(status, EXIT_IO);
+}
+
+#[test]
+fn spec_docs_test()
+{
+  // Every example in the format docs compiles on its own. The ones
+  // starting with a `# name.rlx` comment are the files others include
+
+  let docs = include_str!("../docs/spec-format.md");
+  let examples: Vec<&str> = docs.split("```").skip(1).step_by(2).collect();
+
+  assert!(!examples.is_empty());
+
+  let dir = std::env::temp_dir().join(format!("rolex_spec_docs_test_{}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+
+  for example in examples.iter()
+  {
+    let first = example.trim_start().lines().next().unwrap_or("");
+
+    if first.starts_with("# ") && first.ends_with(".rlx")
+    {
+      File::create(dir.join(&first[2..])).unwrap().write_all(example.as_bytes()).unwrap();
+    }
+  }
+
+  let results: Vec<_> = examples.iter().map(|x| parse_spec(x, Some(&dir.join("docs.rlx")))).collect();
+
+  std::fs::remove_dir_all(&dir).unwrap();
+
+  for (example, result) in examples.iter().zip(results)
+  {
+    let spec = match result
+    {
+      Ok(x) => x,
+      Err(e) => panic!("{}\n{:?}", example, e),
+    };
+
+    assert!(lex::output_lex(&spec, String::new(), lex::Target::C, lex::Priority::First, lex::Layout::Full, false).is_ok(), "{}", example);
+  }
+}
+
 // #[test]
 // fn lex_test()
 // {
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::hash::Hash;
+
+/*
+ * Hopcroft's algorithm for minimizing an automaton, shared by the Dfa and
+ * the Bdfa. Each state has a list of (symbol, next state) transitions, and
+ * a missing transition means the input is rejected. States only end up in
+ * the same block if they give the same answers for every input, so keeping
+ * one state per block gives the smallest automaton that lexes the same.
+ *
+ * Gives the block of every state. Blocks are numbered in the order their
+ * first state appears, so state 0 is always in block 0.
+ */
+pub fn minimize<T: Eq + Hash>(transitions: &[Vec<(usize, usize)>], answers: &[T]) -> Vec<usize>
+{
+  let count = answers.len();
+
+  // Start with a block for each answer
+
+  let mut blocks: Vec<Vec<usize>> = Vec::new();
+  let mut block_of = vec![0; count];
+  let mut by_answer: HashMap<&T, usize> = HashMap::new();
+
+  for (state, answer) in answers.iter().enumerate()
+  {
+    let block = *by_answer.entry(answer).or_insert_with(||
+      {
+        blocks.push(Vec::new());
+        blocks.len() - 1
+      });
+
+    blocks[block].push(state);
+    block_of[state] = block;
+  }
+
+  // Every transition backwards, so the states leading into a block can
+  // be found quickly
+
+  let mut inverse: Vec<Vec<(usize, usize)>> = vec![Vec::new(); count];
+
+  for (source, next) in transitions.iter().enumerate()
+  {
+    for &(symbol, target) in next.iter()
+    {
+      inverse[target].push((symbol, source));
+    }
+  }
+
+  // Missing transitions go to an implicit dead state that never gets a
+  // block, so unlike the textbook algorithm every block starts out as a
+  // splitter, not all but one
+
+  let mut work: Vec<usize> = (0..blocks.len()).collect();
+  let mut in_work = vec![true; blocks.len()];
+  let mut marked = vec![false; count];
+
+  while let Some(splitter) = work.pop()
+  {
+    in_work[splitter] = false;
+
+    // The states leading into the splitter, by symbol
+
+    let mut sources: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+
+    for &target in blocks[splitter].iter()
+    {
+      for &(symbol, source) in inverse[target].iter()
+      {
+        sources.entry(symbol).or_insert_with(Vec::new).push(source);
+      }
+    }
+
+    // Split every block that has states both leading into the splitter on
+    // a symbol and not
+
+    for (_, sources) in sources
+    {
+      let mut touched = Vec::new();
+
+      for &state in sources.iter()
+      {
+        marked[state] = true;
+        touched.push(block_of[state]);
+      }
+
+      touched.sort();
+      touched.dedup();
+
+      for block in touched
+      {
+        let (inside, outside): (Vec<usize>, Vec<usize>) = blocks[block].iter().partition(|&&x| marked[x]);
+
+        if outside.is_empty()
+        {
+          continue;
+        }
+
+        let split = blocks.len();
+
+        for &state in inside.iter()
+        {
+          block_of[state] = split;
+        }
+
+        blocks[block] = outside;
+        blocks.push(inside);
+        in_work.push(false);
+
+        // If the block was still waiting to split others, both halves
+        // have to. Otherwise splitting by the smaller half is enough
+
+        let next = if in_work[block] || blocks[split].len() < blocks[block].len()
+        {
+          split
+        }
+        else
+        {
+          block
+        };
+
+        in_work[next] = true;
+        work.push(next);
+      }
+
+      for &state in sources.iter()
+      {
+        marked[state] = false;
+      }
+    }
+  }
+
+  // Number the blocks in order
+
+  let mut numbers: Vec<Option<usize>> = vec![None; blocks.len()];
+  let mut result = Vec::with_capacity(count);
+  let mut next = 0;
+
+  for &block in block_of.iter()
+  {
+    let number = *numbers[block].get_or_insert_with(||
+      {
+        next += 1;
+        next - 1
+      });
+
+    result.push(number);
+  }
+
+  return result;
+}
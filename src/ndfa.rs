@@ -1,21 +1,66 @@
-use std::collections::HashMap;
 use std::collections::HashSet;
-use std::collections::BTreeSet;
-use std::collections::VecDeque;
 use std::iter::Iterator;
-use std::char;
 
-use regex_syntax::*;
+use regex_syntax::{Expr, Repeater, CharClass, ClassRange};
+
+use ::error::CompileError;
+use ::range::CharRange;
+use ::dfa::{Dfa, MAX_STATES};
 
 pub type NdfaStateId = usize;
-pub type NdfaStateIdSet = BTreeSet<NdfaStateId>;
 
+/*
+ * The highest count a counted repetition like `x{2,5}` can have. Every
+ * count means another copy of the repeated regex, so a typo like
+ * `x{1000000}` is reported instead of running out of memory.
+ */
+pub const MAX_REPEAT: u32 = 1000;
+
+/*
+ * A set of states, kept sorted and without repeats.
+ */
+pub type NdfaStateIdSet = Vec<NdfaStateId>;
+
+/*
+ * Where in the text `^`, `$`, `\A` and `\z` match. Token patterns are
+ * parsed in multi-line mode, so `^` and `$` are the line anchors.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Anchor
+{
+  StartLine,
+  EndLine,
+  StartText,
+  EndText,
+}
+
+/*
+ * A state of an Ndfa. Transitions are labelled with ranges of codepoints,
+ * which can overlap, and a range can lead to several states. Anchors are
+ * empty transitions that can only be taken where the anchor matches.
+ * Accepting states also carry the rank of their answer, so that when a Dfa
+ * state could accept several answers, the one with the lowest rank wins.
+ *
+ * For a token with trailing context, mark is set on the state where its
+ * own pattern ends, and in_context on every state where some of the
+ * context has been read. Both hold the token's answer.
+ *
+ * For a token with not-followed-by, restricts is set on the states that
+ * read the char after it, which only count when the token is what the Dfa
+ * would accept there. The state they lead to rejects.
+ */
 #[derive(Clone)]
 pub struct NdfaState
 {
-  pub next : HashMap<char, NdfaStateId>,
+  pub next : Vec<(CharRange, NdfaStateId)>,
   pub e : Vec<NdfaStateId>,
-  pub answer : Option<usize>
+  pub anchors : Vec<(Anchor, NdfaStateId)>,
+  pub answer : Option<usize>,
+  pub rank : usize,
+  pub mark : Option<usize>,
+  pub in_context : Option<usize>,
+  pub restricts : Option<usize>,
+  pub rejects : bool,
 }
 
 impl NdfaState
@@ -24,23 +69,36 @@ impl NdfaState
   {
     NdfaState
     {
-      next: HashMap::new(),
+      next: Vec::new(),
       e: Vec::new(),
+      anchors: Vec::new(),
       answer: None,
+      rank: 0,
+      mark: None,
+      in_context: None,
+      restricts: None,
+      rejects: false,
     }
   }
-  fn new_directed(letter : char, index : NdfaStateId) -> NdfaState
-  {
-    let mut res = NdfaState::new_empty();
-    res.next.insert(letter, index);
-    return res;
-  }
-  fn new_e(index : NdfaStateId) -> NdfaState
-  {
-    let mut res = NdfaState::new_empty();
-    res.e.push(index);
-    return res;
-  }
+}
+
+/*
+ * A regex to build into an Ndfa, along with the answer it gives, the mode
+ * it can be matched in, and its rank for breaking ties with other rules.
+ * A rule with trailing context only matches when the context comes right
+ * after the regex, without the context being part of the match. A rule
+ * with not-followed-by is an error when one of its chars comes right after
+ * the match instead. The name is only used in errors.
+ */
+pub struct Rule<'a>
+{
+  pub name : &'a str,
+  pub regex : &'a Expr,
+  pub trailing : Option<&'a Expr>,
+  pub not_followed_by : Option<&'a Expr>,
+  pub answer : usize,
+  pub mode : usize,
+  pub rank : usize,
 }
 
 /*
@@ -48,11 +106,26 @@ impl NdfaState
  * an Ndfa can be very naturally constructing from a regex. Ndfa's are
  * not useful to execute, but can be converted to an equivalent Dfa, which
  * can be executed easily.
+ *
+ * A lexer with several modes has one start state per mode, so the
+ * tokens of a mode are only reachable from that mode's start state.
  */
 pub struct Ndfa
 {
-  pub states : Vec<NdfaState>
+  pub states : Vec<NdfaState>,
+  pub starts : Vec<NdfaStateId>,
 }
+/*
+ * A piece of an Ndfa being built, which is entered at its start state and
+ * left from its end state. Every regex builds into one.
+ */
+#[derive(Clone, Copy)]
+struct Fragment
+{
+  start : NdfaStateId,
+  end : NdfaStateId,
+}
+
 impl Ndfa
 {
   fn new() -> Ndfa
@@ -60,141 +133,339 @@ impl Ndfa
     Ndfa
     {
       states: Vec::new(),
+      starts: vec![0],
     }
   }
 
-  pub fn e_closure(&self, index: NdfaStateId) -> NdfaStateIdSet
+  /*
+   * Builds an Ndfa that accepts any of the rules. State i is the start
+   * state of mode i.
+   */
+  pub fn from_regexes(r : Vec<Rule>, mode_count : usize) -> Result<Ndfa, CompileError>
   {
-    let mut next: VecDeque<NdfaStateId> = VecDeque::new();
-    let mut visited: HashSet<NdfaStateId> = HashSet::new();
-
-    let mut result = NdfaStateIdSet::new();
+    let mut res = Ndfa::new();
 
-    next.push_back(index);
-    while !next.is_empty()
+    res.starts = (0..mode_count).collect();
+    for _ in 0..mode_count
     {
-      let cur = next.pop_front().unwrap();
-      let state = self.states.get(cur).unwrap();
+      res.states.push(NdfaState::new_empty());
+    }
 
-      for e in state.e.iter()
+    for rule in r.iter()
+    {
+      if let Err(mut e) = res.add_rule(rule)
       {
-        if !visited.contains(e)
-        {
-          visited.insert(*e);
-          next.push_back(*e);
-        }
+        e.token = Some(String::from(rule.name));
+        return Err(e);
       }
-
-      result.insert(cur);
     }
 
-    return result;
+    return Ok(res);
   }
 
-  pub fn from_regexes(r : Vec<&Expr>) -> Ndfa
+  fn add_rule(&mut self, rule : &Rule) -> Result<(), CompileError>
   {
-    let mut res = Ndfa::new();
+    let fragment = match rule.trailing
+    {
+      Some(trailing) =>
+      {
+        Ndfa::check_anchors(rule.regex, true, false)?;
+        Ndfa::check_anchors(trailing, false, true)?;
+        Ndfa::check_trailing(rule, trailing)?;
 
-    res.states.push(NdfaState::new_empty());
+        self.build_trailing(rule.regex, trailing, rule.answer)?
+      }
+      None =>
+      {
+        Ndfa::check_anchors(rule.regex, true, true)?;
 
-    for (i, exp) in r.iter().enumerate()
+        self.build_regex_states(rule.regex)?
+      }
+    };
+
+    self.states[fragment.end].answer = Some(rule.answer);
+    self.states[fragment.end].rank = rule.rank;
+    self.states[rule.mode].e.push(fragment.start);
+
+    if let Some(class) = rule.not_followed_by
     {
-      let start = res.states.len();
+      self.build_restriction(fragment.end, class, rule.answer)?;
+    }
 
-      res.append_ndfa(&Ndfa::from_regex_with_answer(*exp, i));
+    return Ok(());
+  }
+
+  /*
+   * Leads from the end of a token to a rejecting state on any char of the
+   * class. The states in between are marked with the token's answer, so
+   * the Dfa only keeps them where the token would be accepted.
+   */
+  fn build_restriction(&mut self, end : NdfaStateId, class : &Expr, answer : usize) -> Result<(), CompileError>
+  {
+    let before = self.states.len();
+    let follow = self.build_regex_states(class)?;
 
-      res.states.get_mut(0).unwrap().e.push(start);
+    for id in before..self.states.len()
+    {
+      self.states[id].restricts = Some(answer);
     }
 
-    return res;
+    self.states[follow.end].restricts = None;
+    self.states[follow.end].rejects = true;
+    self.states[end].e.push(follow.start);
+
+    return Ok(());
   }
 
-  #[allow(dead_code)]
-  pub fn from_regex(r : &Expr) -> Ndfa
+  /*
+   * Checks that the lexers can tell where a token with trailing context
+   * ends. They take the last place its regex ended before the context
+   * finished, so that must not happen again once the context has started,
+   * as with `a+/ab`. Building the Dfa of the rule on its own finds that.
+   */
+  fn check_trailing(rule : &Rule, trailing : &Expr) -> Result<(), CompileError>
   {
-    let mut res = Ndfa::build_regex_states(r);
+    let mut single = Ndfa::new();
+    single.states.push(NdfaState::new_empty());
 
-    let last = res.states.len() - 1;
-    res.states.get_mut(last).unwrap().answer = Some(1);
+    let fragment = single.build_trailing(rule.regex, trailing, rule.answer)?;
 
-    return res;
+    single.states[fragment.end].answer = Some(rule.answer);
+    single.states[0].e.push(fragment.start);
+
+    Dfa::from_ndfa(&single)?;
+
+    return Ok(());
   }
 
-  pub fn from_regex_with_answer(r : &Expr, answer : usize) -> Ndfa
+  pub fn from_regex(r : &Expr) -> Result<Ndfa, CompileError>
   {
-    let mut res = Ndfa::build_regex_states(r);
+    let rule = Rule { name: "", regex: r, trailing: None, not_followed_by: None, answer: 1, mode: 0, rank: 0 };
 
-    let last = res.states.len() - 1;
-    res.states.get_mut(last).unwrap().answer = Some(answer);
+    return Ndfa::from_regexes(vec![rule], 1);
+  }
 
-    return res;
+  /*
+   * How a regex construct is written, for errors about it.
+   */
+  fn describe(r : &Expr) -> &'static str
+  {
+    match *r
+    {
+      Expr::LiteralBytes { .. } | Expr::ClassBytes(_) => "bytes above `\\x7F` with the `u` flag off",
+      Expr::AnyByte | Expr::AnyByteNoNL => "`.` with the `u` flag off",
+      Expr::StartLine => "`^`",
+      Expr::EndLine => "`$`",
+      Expr::StartText => "`\\A`",
+      Expr::EndText => "`\\z`",
+      Expr::WordBoundary | Expr::WordBoundaryAscii => "`\\b`",
+      Expr::NotWordBoundary | Expr::NotWordBoundaryAscii => "`\\B`",
+      _ => "this regex",
+    }
   }
 
   /*
-   * Builds the states of an Ndfa corresponding to the regular expression r.
-   * Note, this doesn't mark any states as final. That needs to be done by
-   * a driver function. I didn't want to have to deal with managing the
-   * final state (recursive calls make this messier), so the final state is
-   * always implictly the last state in the Vec. (simplifies this code too)
+   * Describes a regex construct the Ndfa can't be built from.
    */
-  fn build_regex_states(r : &Expr) -> Ndfa
+  fn unsupported(r : &Expr) -> CompileError
+  {
+    return CompileError::new(&format!("{} isn't supported.", Ndfa::describe(r)));
+  }
+
+  /*
+   * Checks that start anchors only come where nothing can have been read
+   * before them in a token, and end anchors where nothing can be read after.
+   * Like flex's, the generated lexers only look around whole tokens, so an
+   * anchor in the middle of one could never match.
+   */
+  fn check_anchors(r : &Expr, first : bool, last : bool) -> Result<(), CompileError>
   {
     match *r
     {
-      Expr::Empty =>
+      Expr::StartLine | Expr::StartText if !first =>
+      {
+        return Err(CompileError::new(&format!("{} can only be used at the start of a token.", Ndfa::describe(r))));
+      }
+      Expr::EndLine | Expr::EndText if !last =>
       {
-        let mut res = Ndfa::new();
+        return Err(CompileError::new(&format!("{} can only be used at the end of a token.", Ndfa::describe(r))));
+      }
+      Expr::Group{ref e, ..} =>
+      {
+        return Ndfa::check_anchors(e, first, last);
+      }
+      Expr::Repeat{ref e, r, ..} =>
+      {
+        // A second time round, the first time was read before
 
-        res.states.push(NdfaState::new_e(1));
-        res.states.push(NdfaState::new_empty());
+        let once = match r
+        {
+          Repeater::ZeroOrOne => true,
+          Repeater::Range { max: Some(max), .. } => max <= 1,
+          _ => false,
+        };
 
-        return res;
+        return Ndfa::check_anchors(e, first && once, last && once);
       }
-
-      Expr::Literal{ref chars, ref casei} =>
+      Expr::Concat(ref exprs) =>
       {
-        if *casei
+        for (i, e) in exprs.iter().enumerate()
         {
-          unimplemented!();
-        }
+          let first = first && exprs[..i].iter().all(Ndfa::reads_nothing);
+          let last = last && exprs[(i + 1)..].iter().all(Ndfa::reads_nothing);
 
-        let mut res = Ndfa::new();
+          Ndfa::check_anchors(e, first, last)?;
+        }
 
-        for (i, x) in chars.iter().enumerate()
+        return Ok(());
+      }
+      Expr::Alternate(ref exprs) =>
+      {
+        for e in exprs.iter()
         {
-          res.states.push(NdfaState::new_directed(*x, i + 1));
+          Ndfa::check_anchors(e, first, last)?;
         }
-        res.states.push(NdfaState::new_empty());
 
-        return res;
+        return Ok(());
       }
+      _ => Ok(()),
+    }
+  }
 
-      Expr::Class(ref c) =>
+  /*
+   * Whether a regex never reads a char, like an anchor.
+   */
+  fn reads_nothing(r : &Expr) -> bool
+  {
+    match *r
+    {
+      Expr::Empty => true,
+      Expr::StartLine | Expr::EndLine | Expr::StartText | Expr::EndText => true,
+      Expr::Group{ref e, ..} | Expr::Repeat{ref e, ..} => Ndfa::reads_nothing(e),
+      Expr::Concat(ref exprs) | Expr::Alternate(ref exprs) => exprs.iter().all(Ndfa::reads_nothing),
+      _ => false,
+    }
+  }
+
+  fn add_state(&mut self) -> NdfaStateId
+  {
+    self.states.push(NdfaState::new_empty());
+    return self.states.len() - 1;
+  }
+
+  /*
+   * Stops a build that has grown too big, which nested repetitions like
+   * `(x{1000}){1000}` can do without any one count being too high.
+   */
+  fn check_size(&self) -> Result<(), CompileError>
+  {
+    if self.states.len() > MAX_STATES
+    {
+      return Err(CompileError::new(&format!("the regex would need more than {} states.", MAX_STATES)));
+    }
+
+    return Ok(());
+  }
+
+  /*
+   * Adds the states for the regular expression r to the Ndfa, giving back
+   * the fragment they make up. Note, this doesn't mark any states as final.
+   * That needs to be done by a driver function, on the fragment's end.
+   */
+  fn build_regex_states(&mut self, r : &Expr) -> Result<Fragment, CompileError>
+  {
+    match *r
+    {
+      Expr::Empty =>
       {
-        let mut res = Ndfa::new();
+        let start = self.add_state();
 
-        res.states.push(NdfaState::new_empty());
-        res.states.push(NdfaState::new_empty());
+        return Ok(Fragment { start: start, end: start });
+      }
 
-        for class in c.iter()
+      Expr::Literal{ref chars, ref casei} =>
+      {
+        // Case-insensitive chars match everything they fold to, using the
+        // same simple case folding as the classes regex_syntax folds itself
+
+        let letters = chars.iter().map(|&x|
+        {
+          if !*casei
+          {
+            return vec![CharRange::single(x)];
+          }
+
+          let folded = CharClass::new(vec![ClassRange { start: x, end: x }]).case_fold();
+          return folded.iter().map(|x| CharRange::new(x.start, x.end)).collect();
+        });
+
+        return Ok(self.sequence(letters));
+      }
+
+      Expr::LiteralBytes{ref bytes, ref casei} =>
+      {
+        if !bytes.is_ascii()
         {
-          let first = res.states.get_mut(0).unwrap();
+          return Err(Ndfa::unsupported(r));
+        }
 
-          let class_start = class.start as u32;
-          let class_end = class.end as u32;
+        // Without the `u` flag only ASCII letters have a case
+
+        let letters = bytes.iter().map(|&x|
+        {
+          let letter = x as char;
 
-          for x in (class_start)..(class_end+1)
+          if !*casei || !letter.is_ascii_alphabetic()
           {
-            first.next.insert(char::from_u32(x).unwrap(), 1);
+            return vec![CharRange::single(letter)];
           }
+
+          return vec![CharRange::single(letter.to_ascii_lowercase()), CharRange::single(letter.to_ascii_uppercase())];
+        });
+
+        return Ok(self.sequence(letters));
+      }
+
+      Expr::AnyChar =>
+      {
+        return Ok(self.ranges(&[CharRange::new('\0', char::MAX)]));
+      }
+
+      Expr::AnyCharNoNL =>
+      {
+        return Ok(self.ranges(&[CharRange::new('\0', '\x09'), CharRange::new('\x0B', char::MAX)]));
+      }
+
+      Expr::Class(ref c) =>
+      {
+        let ranges: Vec<CharRange> = c.iter().map(|x| CharRange::new(x.start, x.end)).collect();
+
+        return Ok(self.ranges(&ranges));
+      }
+
+      // Without the `u` flag classes are made of bytes, but the lexers
+      // match chars, so only the ASCII ones mean the same thing
+
+      Expr::ClassBytes(ref c) =>
+      {
+        if c.iter().any(|x| x.end > 0x7F)
+        {
+          return Err(Ndfa::unsupported(r));
         }
 
-        return res;
+        let ranges: Vec<CharRange> = c.iter().map(|x| CharRange::new(x.start as char, x.end as char)).collect();
+
+        return Ok(self.ranges(&ranges));
       }
 
+      Expr::StartLine => Ok(self.anchor(Anchor::StartLine)),
+      Expr::EndLine => Ok(self.anchor(Anchor::EndLine)),
+      Expr::StartText => Ok(self.anchor(Anchor::StartText)),
+      Expr::EndText => Ok(self.anchor(Anchor::EndText)),
+
       Expr::Group{ref e, ..} =>
       {
-        return Ndfa::build_regex_states(e.as_ref());
+        return self.build_regex_states(e.as_ref());
       }
 
       Expr::Repeat{ref e, ref r, ..} =>
@@ -203,151 +474,328 @@ impl Ndfa
         {
           Repeater::ZeroOrOne =>
           {
-            let mut res = Ndfa::new();
-
-            res.append_ndfa(&Ndfa::build_regex_states(e.as_ref()));
+            let inner = self.build_regex_states(e.as_ref())?;
+            let start = self.add_state();
+            let end = self.add_state();
 
-            let last_index = res.states.len() - 1;
-            res.states.get_mut(0).unwrap().e.push(last_index);
+            self.states[start].e.push(inner.start);
+            self.states[start].e.push(end);
+            self.states[inner.end].e.push(end);
 
-            return res;
+            return Ok(Fragment { start: start, end: end });
           },
           Repeater::ZeroOrMore =>
           {
-            let mut res = Ndfa::new();
-
-            res.append_ndfa(&Ndfa::build_regex_states(e.as_ref()));
-
-            let last_index = res.states.len() - 1;
-            res.states.get_mut(0).unwrap().e.push(last_index);
-            res.states.get_mut(last_index).unwrap().e.push(0);
-
-            return res;
+            return self.zero_or_more(e.as_ref());
           },
           Repeater::OneOrMore =>
           {
-            return Ndfa::build_regex_states(
-              &Expr::Concat(
-                vec![
-                  e.as_ref().clone(),
-                  Expr::Repeat{
-                    e: e.clone(),
-                    r: Repeater::ZeroOrMore,
-                    greedy: true,
-                  }
-                ]
-                )
-              );
+            let inner = self.build_regex_states(e.as_ref())?;
+            let end = self.add_state();
+
+            self.states[inner.end].e.push(inner.start);
+            self.states[inner.end].e.push(end);
+
+            return Ok(Fragment { start: inner.start, end: end });
           },
           Repeater::Range { min, max } =>
           {
+            let count = max.unwrap_or(min);
+
+            if count > MAX_REPEAT
+            {
+              return Err(CompileError::new(&format!("repetition counts above {} aren't supported.", MAX_REPEAT)));
+            }
+
+            let copies = self.repeat(e.as_ref(), min)?;
+
             if let Some(max) = max
             {
-              let mut alternates : Vec<Expr> = Vec::new();
+              // Each copy past min can be skipped, which skips the rest too
+
+              let end = self.add_state();
+              let mut last = copies.end;
 
-              for i in min..(max+1)
+              for _ in min..max
               {
-                alternates.push(
-                  Expr::Concat((0..i).map(|_| e.as_ref().clone()).collect())
-                  );
+                let inner = self.build_regex_states(e.as_ref())?;
+                self.check_size()?;
+
+                self.states[last].e.push(inner.start);
+                self.states[last].e.push(end);
+                last = inner.end;
               }
 
-              return Ndfa::build_regex_states(
-                &Expr::Alternate(alternates)
-                )
+              self.states[last].e.push(end);
+
+              return Ok(Fragment { start: copies.start, end: end });
             }
             else
             {
-              let mut concat : Vec<Expr> = (0..min).map(|_| e.as_ref().clone()).collect();
-
-              concat.push(
-                    Expr::Repeat{
-                      e: e.clone(),
-                      r: Repeater::ZeroOrMore,
-                      greedy: true,
-                    });
-
-              return Ndfa::build_regex_states(
-                &Expr::Concat(concat)
-                );
+              let rest = self.zero_or_more(e.as_ref())?;
+
+              self.states[copies.end].e.push(rest.start);
+
+              return Ok(Fragment { start: copies.start, end: rest.end });
             }
           }
         }
       }
 
+      // An empty concatenation matches the empty string, and an empty
+      // alternation matches nothing
+
       Expr::Concat(ref exprs) =>
       {
-        assert!(exprs.len() > 0);
+        return self.concat(exprs.iter());
+      }
 
-        let mut res = Ndfa::new();
+      Expr::Alternate(ref exprs) =>
+      {
+        let start = self.add_state();
+        let end = self.add_state();
 
         for e in exprs.iter()
         {
-          res.append_ndfa(&Ndfa::build_regex_states(e));
+          let inner = self.build_regex_states(e)?;
 
-          let last = res.states.len() - 1;
-          res.states.get_mut(last).unwrap().e.push(last + 1);
+          self.states[start].e.push(inner.start);
+          self.states[inner.end].e.push(end);
         }
 
-        res.states.push(NdfaState::new_empty());
-
-        return res;
+        return Ok(Fragment { start: start, end: end });
       }
 
-      Expr::Alternate(ref exprs) =>
+      _ => Err(Ndfa::unsupported(r))
+    }
+  }
+
+  /*
+   * Builds r followed by its trailing context. The context is built twice,
+   * with every char read in the first copy leading into the second, so
+   * the second copy's states are the ones where some of it has been read.
+   */
+  fn build_trailing(&mut self, r : &Expr, trailing : &Expr, answer : usize) -> Result<Fragment, CompileError>
+  {
+    let head = self.build_regex_states(r)?;
+
+    let before = self.states.len();
+    let first = self.build_regex_states(trailing)?;
+    let middle = self.states.len();
+    let rest = self.build_regex_states(trailing)?;
+
+    for id in before..middle
+    {
+      for transition in self.states[id].next.iter_mut()
       {
-        assert!(exprs.len() > 0);
+        transition.1 += middle - before;
+      }
+    }
 
-        let mut res = Ndfa::new();
-        let mut to_fixup = Vec::new();
+    for id in middle..self.states.len()
+    {
+      self.states[id].in_context = Some(answer);
+    }
 
-        res.states.push(NdfaState::new_empty());
+    let end = self.add_state();
 
-        for e in exprs.iter()
-        {
-          let start = res.states.len();
+    self.states[head.end].mark = Some(answer);
+    self.states[head.end].e.push(first.start);
+    self.states[first.end].e.push(end);
+    self.states[rest.end].e.push(end);
 
-          res.append_ndfa(&Ndfa::build_regex_states(e));
+    return Ok(Fragment { start: head.start, end: end });
+  }
 
-          let end = res.states.len() - 1;
+  fn anchor(&mut self, anchor : Anchor) -> Fragment
+  {
+    let start = self.add_state();
+    let end = self.add_state();
 
-          res.states.get_mut(0).unwrap().e.push(start);
-          to_fixup.push(end);
-        }
+    self.states[start].anchors.push((anchor, end));
 
-        res.states.push(NdfaState::new_empty());
+    return Fragment { start: start, end: end };
+  }
 
-        let last = res.states.len() - 1;
-        for id in to_fixup
-        {
-          res.states.get_mut(id).unwrap().e.push(last);
-        }
+  /*
+   * Builds a fragment that matches one char from each list of ranges in
+   * turn, like the letters of a literal.
+   */
+  fn sequence<I: Iterator<Item = Vec<CharRange>>>(&mut self, letters: I) -> Fragment
+  {
+    let start = self.add_state();
+    let mut end = start;
+
+    for ranges in letters
+    {
+      let next = self.add_state();
 
-        return res;
+      for range in ranges
+      {
+        self.states[end].next.push((range, next));
       }
+      end = next;
+    }
+
+    return Fragment { start: start, end: end };
+  }
+
+  /*
+   * Builds a fragment that matches any one char in the ranges.
+   */
+  fn ranges(&mut self, ranges: &[CharRange]) -> Fragment
+  {
+    let start = self.add_state();
+    let end = self.add_state();
 
-      _ => unimplemented!()
+    for &range in ranges.iter()
+    {
+      self.states[start].next.push((range, end));
     }
+
+    return Fragment { start: start, end: end };
   }
 
-  fn append_ndfa(&mut self, x: &Ndfa)
+  /*
+   * Builds each regex in turn, with the end of each leading to the start of
+   * the next.
+   */
+  fn concat<'a, I: Iterator<Item = &'a Expr>>(&mut self, exprs: I) -> Result<Fragment, CompileError>
   {
-    let base = self.states.len();
+    let start = self.add_state();
+    let mut end = start;
 
-    for state in x.states.iter()
+    for e in exprs
     {
-      let mut state = state.clone();
+      let inner = self.build_regex_states(e)?;
+
+      self.states[end].e.push(inner.start);
+      end = inner.end;
+
+      self.check_size()?;
+    }
+
+    return Ok(Fragment { start: start, end: end });
+  }
+
+  /*
+   * Builds count copies of e one after another. Each copy gets its own
+   * states, but the regex itself is never copied.
+   */
+  fn repeat(&mut self, e : &Expr, count : u32) -> Result<Fragment, CompileError>
+  {
+    return self.concat((0..count).map(|_| e));
+  }
+
+  fn zero_or_more(&mut self, e : &Expr) -> Result<Fragment, CompileError>
+  {
+    let inner = self.build_regex_states(e)?;
+    let start = self.add_state();
+    let end = self.add_state();
+
+    self.states[start].e.push(inner.start);
+    self.states[start].e.push(end);
+    self.states[inner.end].e.push(inner.start);
+    self.states[inner.end].e.push(end);
 
-      for (_, id) in state.next.iter_mut()
+    return Ok(Fragment { start: start, end: end });
+  }
+}
+
+/*
+ * The e-closures of an Ndfa's states. Each one is only worked out the
+ * first time it's asked for, since building a Dfa asks for the same ones
+ * over and over.
+ */
+pub struct Closures<'a>
+{
+  ndfa : &'a Ndfa,
+  cache : Vec<Option<NdfaStateIdSet>>,
+  visited : Vec<bool>,
+}
+impl<'a> Closures<'a>
+{
+  pub fn new(ndfa: &'a Ndfa) -> Closures<'a>
+  {
+    Closures
+    {
+      ndfa: ndfa,
+      cache: vec![None; ndfa.states.len()],
+      visited: vec![false; ndfa.states.len()],
+    }
+  }
+
+  pub fn get(&mut self, index: NdfaStateId) -> &NdfaStateIdSet
+  {
+    if self.cache[index].is_none()
+    {
+      let mut next: Vec<NdfaStateId> = vec![index];
+      let mut result = NdfaStateIdSet::new();
+
+      self.visited[index] = true;
+
+      while let Some(cur) = next.pop()
       {
-        *id = *id + base;
+        for &e in self.ndfa.states[cur].e.iter()
+        {
+          if !self.visited[e]
+          {
+            self.visited[e] = true;
+            next.push(e);
+          }
+        }
+
+        result.push(cur);
       }
-      for id in state.e.iter_mut()
+
+      // Only the states just visited need clearing for next time
+
+      for &id in result.iter()
       {
-        *id = *id + base;
+        self.visited[id] = false;
       }
 
-      self.states.push(state);
+      result.sort();
+      self.cache[index] = Some(result);
     }
+
+    return self.cache[index].as_ref().unwrap();
+  }
+
+  /*
+   * Adds to a set of states, closed already, everything reachable through
+   * the anchors that match. For example, the states a token can start in at
+   * the start of a line, or the ones it can end in before a newline.
+   */
+  pub fn anchored(&mut self, set: &NdfaStateIdSet, anchors: &[Anchor]) -> NdfaStateIdSet
+  {
+    let ndfa = self.ndfa;
+    let mut result = set.clone();
+    let mut seen: HashSet<NdfaStateId> = set.iter().cloned().collect();
+    let mut next = set.clone();
+
+    while let Some(cur) = next.pop()
+    {
+      for &(anchor, target) in ndfa.states[cur].anchors.iter()
+      {
+        if !anchors.contains(&anchor)
+        {
+          continue;
+        }
+
+        for &id in self.get(target).iter()
+        {
+          if seen.insert(id)
+          {
+            result.push(id);
+            next.push(id);
+          }
+        }
+      }
+    }
+
+    result.sort();
+    return result;
   }
 }
+
@@ -0,0 +1,193 @@
+use std::char;
+use std::collections::BTreeMap;
+
+/*
+ * An inclusive range of codepoints. Automata label their transitions with
+ * these rather than single chars, so a class like `[^"]` is a couple of
+ * ranges instead of a million separate transitions.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct CharRange
+{
+  pub start : char,
+  pub end : char,
+}
+impl CharRange
+{
+  pub fn new(start: char, end: char) -> CharRange
+  {
+    CharRange
+    {
+      start: start,
+      end: end,
+    }
+  }
+
+  pub fn single(letter: char) -> CharRange
+  {
+    return CharRange::new(letter, letter);
+  }
+
+  /*
+   * Whether other starts right after this range ends, so the two could be
+   * one range. The surrogates between them don't count as a gap.
+   */
+  pub fn precedes(&self, other: &CharRange) -> bool
+  {
+    let end = self.end as u32;
+    let start = other.start as u32;
+
+    return end + 1 == start || (end == 0xD7FF && start == 0xE000);
+  }
+
+  /*
+   * Splits the range into sequences of byte ranges, so that the UTF-8
+   * encodings of its chars are exactly the byte strings some sequence
+   * matches. This is the approach of the utf8-ranges crate. The range is
+   * cut wherever the encoded length changes, and then until any byte that
+   * varies is followed only by bytes that cover every continuation byte.
+   */
+  pub fn utf8_sequences(&self) -> Vec<Vec<(u8, u8)>>
+  {
+    let mut result = Vec::new();
+    let mut stack = vec![(self.start as u32, self.end as u32)];
+
+    'ranges: while let Some((start, end)) = stack.pop()
+    {
+      // Surrogates can't be encoded, so a range across them skips them
+
+      if start < 0xD800 && end > 0xDFFF
+      {
+        stack.push((0xE000, end));
+        stack.push((start, 0xD7FF));
+        continue;
+      }
+
+      for &max in [0x7F, 0x7FF, 0xFFFF].iter()
+      {
+        if start <= max && end > max
+        {
+          stack.push((max + 1, end));
+          stack.push((start, max));
+          continue 'ranges;
+        }
+      }
+
+      if end > 0x7F
+      {
+        for i in 1..4
+        {
+          let mask = (1 << (6 * i)) - 1;
+
+          if start & !mask == end & !mask
+          {
+            continue;
+          }
+
+          if start & mask != 0
+          {
+            stack.push(((start | mask) + 1, end));
+            stack.push((start, start | mask));
+            continue 'ranges;
+          }
+
+          if end & mask != mask
+          {
+            stack.push((end & !mask, end));
+            stack.push((start, (end & !mask) - 1));
+            continue 'ranges;
+          }
+        }
+      }
+
+      let mut low = [0; 4];
+      let mut high = [0; 4];
+
+      let low = char::from_u32(start).unwrap().encode_utf8(&mut low).as_bytes();
+      let high = char::from_u32(end).unwrap().encode_utf8(&mut high).as_bytes();
+
+      result.push(low.iter().cloned().zip(high.iter().cloned()).collect());
+    }
+
+    return result;
+  }
+
+  /*
+   * Builds a range from codepoints, trimming off any surrogates at either
+   * end, since those aren't chars. Gives None if nothing is left.
+   */
+  fn from_u32(start: u32, end: u32) -> Option<CharRange>
+  {
+    let surrogates = 0xD800..0xE000;
+
+    let start = if surrogates.contains(&start) { 0xE000 } else { start };
+    let end = if surrogates.contains(&end) { 0xD7FF } else { end };
+
+    if start > end
+    {
+      return None;
+    }
+
+    return Some(CharRange::new(char::from_u32(start).unwrap(), char::from_u32(end).unwrap()));
+  }
+}
+
+/*
+ * Splits a list of possibly overlapping ranges, each labelled with a value,
+ * into sorted disjoint ranges. Each comes with every value whose range
+ * covers it, sorted and without repeats. Codepoints no range covers are
+ * left out.
+ */
+pub fn partition<T: Clone + Ord>(ranges: &[(CharRange, T)]) -> Vec<(CharRange, Vec<T>)>
+{
+  // Every range opens at its start and closes just past its end. Closes
+  // sort before opens, so the active values are right at each boundary
+
+  let mut events: Vec<(u32, bool, usize)> = Vec::new();
+
+  for (i, &(range, _)) in ranges.iter().enumerate()
+  {
+    events.push((range.start as u32, true, i));
+    events.push((range.end as u32 + 1, false, i));
+  }
+
+  events.sort();
+
+  let mut result: Vec<(CharRange, Vec<T>)> = Vec::new();
+  let mut active: BTreeMap<T, usize> = BTreeMap::new();
+  let mut last = 0;
+
+  for (point, open, i) in events
+  {
+    if point > last && !active.is_empty()
+    {
+      if let Some(range) = CharRange::from_u32(last, point - 1)
+      {
+        result.push((range, active.keys().cloned().collect()));
+      }
+    }
+    last = point;
+
+    let value = &ranges[i].1;
+
+    if open
+    {
+      *active.entry(value.clone()).or_insert(0) += 1;
+    }
+    else
+    {
+      let count = active[value] - 1;
+
+      if count == 0
+      {
+        active.remove(value);
+      }
+      else
+      {
+        active.insert(value.clone(), count);
+      }
+    }
+  }
+
+  return result;
+}
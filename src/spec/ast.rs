@@ -0,0 +1,139 @@
+/*
+ * The syntax tree of a single .rlx file, exactly as written. Nothing is
+ * resolved here, names, modes and includes are only looked up when the
+ * tree is read into a Spec. Every node keeps the span it was parsed from.
+ */
+
+/*
+ * A range of bytes in the text of a .rlx file, end exclusive.
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Span
+{
+  pub start : usize,
+  pub end : usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Ident
+{
+  pub name : String,
+  pub span : Span,
+}
+
+/*
+ * How a pattern was written. A regex runs to the end of its line, a quoted
+ * regex is wrapped in backticks and can span several lines, and a literal
+ * is a string literal that's matched exactly.
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PatternKind
+{
+  Regex,
+  Quoted,
+  Literal,
+}
+
+/*
+ * A pattern, or an attribute value. The text has its quotes and escapes
+ * already removed, so for a literal it's the exact text to match. Offsets
+ * holds where in the file each char of the text came from, which is how
+ * regex errors are pointed at the right column. Every char of a literal
+ * points at its opening quote.
+ */
+#[derive(Clone, Debug)]
+pub struct Pattern
+{
+  pub kind : PatternKind,
+  pub text : String,
+  pub offsets : Vec<usize>,
+  pub span : Span,
+}
+
+/*
+ * An entry of an attribute list, like `skip` or `push = string`.
+ */
+#[derive(Clone, Debug)]
+pub struct Attribute
+{
+  pub key : Ident,
+  pub value : Option<Pattern>,
+  pub span : Span,
+}
+
+/*
+ * `name (attributes): pattern`, with any `///` lines above it.
+ */
+#[derive(Clone, Debug)]
+pub struct TokenDecl
+{
+  pub doc : Vec<String>,
+  pub name : Ident,
+  pub attributes : Vec<Attribute>,
+  pub pattern : Pattern,
+  pub span : Span,
+}
+
+/*
+ * `name = pattern`. Attributes are parsed so they can be reported, but a
+ * definition can't have any.
+ */
+#[derive(Clone, Debug)]
+pub struct DefinitionDecl
+{
+  pub name : Ident,
+  pub attributes : Vec<Attribute>,
+  pub pattern : Pattern,
+  pub span : Span,
+}
+
+/*
+ * `mode name { items }`.
+ */
+#[derive(Clone, Debug)]
+pub struct ModeBlock
+{
+  pub name : Ident,
+  pub items : Vec<Item>,
+  pub span : Span,
+}
+
+#[derive(Clone, Debug)]
+pub struct KeywordDecl
+{
+  pub doc : Vec<String>,
+  pub name : Ident,
+}
+
+/*
+ * `keywords base (attributes) { words }`.
+ */
+#[derive(Clone, Debug)]
+pub struct KeywordBlock
+{
+  pub base : Ident,
+  pub attributes : Vec<Attribute>,
+  pub keywords : Vec<KeywordDecl>,
+  pub span : Span,
+}
+
+/*
+ * `include "path"`, or `import "path" as alias`.
+ */
+#[derive(Clone, Debug)]
+pub struct Include
+{
+  pub path : String,
+  pub alias : Option<Ident>,
+  pub span : Span,
+}
+
+#[derive(Clone, Debug)]
+pub enum Item
+{
+  Token(TokenDecl),
+  Definition(DefinitionDecl),
+  Mode(ModeBlock),
+  Keywords(KeywordBlock),
+  Include(Include),
+}
@@ -0,0 +1,1213 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use regex_syntax::{Expr, ExprBuilder, Repeater};
+
+use ::diagnostic::{Diagnostics, Location};
+use ::ndfa::Ndfa;
+use ::dfa::Dfa;
+use self::ast::{Item, Ident, Attribute, Pattern, PatternKind, Span};
+
+pub mod ast;
+mod parser;
+
+/*
+ * A single token declaration from a .rlx file. The doc lines come from
+ * any `///` comments directly above the declaration and are carried
+ * through to the generated token enums.
+ *
+ * A `nocase` token has its regex parsed ignoring case already, so the flag
+ * only matters to keywords, which the generated lexers then compare with
+ * the ASCII letters folded.
+ *
+ * A pattern written `r/s` only matches r when s comes right after it, like
+ * in flex. Regex holds r and trailing holds s.
+ *
+ * A token with a `not-followed-by` class is a lexing error when it's
+ * matched right before a char in the class, like SDF's follow restrictions.
+ */
+pub struct Token
+{
+  pub name : String,
+  pub doc : Vec<String>,
+  pub regex : Expr,
+  pub trailing : Option<Expr>,
+  pub not_followed_by : Option<Expr>,
+  pub skip : bool,
+  pub nocase : bool,
+  pub priority : isize,
+  pub mode : usize,
+  pub action : Action,
+  pub keyword : Option<Keyword>,
+}
+
+/*
+ * A keyword from a `keywords base { ... }` block. Keywords aren't built
+ * into the automaton. Instead, the generated lexers look up the text of
+ * every match of the base token in a small keyword table.
+ */
+pub struct Keyword
+{
+  pub base : usize,
+  pub text : String,
+}
+
+/*
+ * What happens to the lexer's mode stack when a token is matched. Modes
+ * are referred to by their index in `Spec::modes`.
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Action
+{
+  None,
+  Switch(usize),
+  Push(usize),
+  Pop,
+}
+
+/*
+ * Everything read out of a .rlx file, in declaration order. Tokens declared
+ * outside of a `mode name { ... }` block belong to the first mode, `default`,
+ * which is also the mode every lexer starts in.
+ */
+pub struct Spec
+{
+  pub tokens : Vec<Token>,
+  pub modes : Vec<String>,
+}
+
+fn valid_identifier(text: &str) -> bool
+{
+  let mut read_first = false;
+
+  for c in text.chars()
+  {
+    if !read_first && !c.is_alphabetic()
+    {
+      return false;
+    }
+    else if !c.is_alphabetic() && !c.is_numeric()
+    {
+      return false;
+    }
+
+    read_first = true;
+  }
+  return true;
+}
+
+
+/*
+ * The lowercased description of a regex error, without the position, which
+ * is shown as a column instead.
+ */
+fn regex_message(e: &regex_syntax::Error) -> String
+{
+  let text = e.kind().to_string();
+  let mut chars = text.chars();
+
+  match chars.next()
+  {
+    Some(c) => c.to_lowercase().chain(chars).collect(),
+    None => text,
+  }
+}
+
+/*
+ * Whether a regex can match without consuming anything. A token like that
+ * would keep the generated lexers from ever moving forward.
+ */
+fn matches_empty(e: &Expr) -> bool
+{
+  match *e
+  {
+    Expr::Empty => true,
+    Expr::StartLine | Expr::EndLine | Expr::StartText | Expr::EndText => true,
+    Expr::WordBoundary | Expr::NotWordBoundary => true,
+    Expr::WordBoundaryAscii | Expr::NotWordBoundaryAscii => true,
+    Expr::Group { ref e, .. } => matches_empty(e),
+    Expr::Repeat { ref e, r, .. } =>
+    {
+      match r
+      {
+        Repeater::ZeroOrOne | Repeater::ZeroOrMore => true,
+        Repeater::OneOrMore => matches_empty(e),
+        Repeater::Range { min, .. } => min == 0 || matches_empty(e),
+      }
+    }
+    Expr::Concat(ref es) => es.iter().all(matches_empty),
+    Expr::Alternate(ref es) => es.iter().any(matches_empty),
+    _ => false,
+  }
+}
+
+/*
+ * Whether a regex always matches exactly one char, like a class.
+ */
+fn matches_one_char(e: &Expr) -> bool
+{
+  match *e
+  {
+    Expr::Class(_) | Expr::ClassBytes(_) => true,
+    Expr::AnyChar | Expr::AnyCharNoNL | Expr::AnyByte | Expr::AnyByteNoNL => true,
+    Expr::Literal { ref chars, .. } => chars.len() == 1,
+    Expr::LiteralBytes { ref bytes, .. } => bytes.len() == 1,
+    Expr::Group { ref e, .. } => matches_one_char(e),
+    Expr::Alternate(ref es) => es.iter().all(matches_one_char),
+    _ => false,
+  }
+}
+
+/*
+ * Gives the chars of a regex that aren't escaped or inside a character
+ * class, with their byte index. Classes are read the way the regex parser
+ * reads them, so a `]` right after the `[` or `[^` is a literal, and ASCII
+ * classes like `[:alpha:]` can be inside one.
+ */
+fn plain_chars(regex: &str) -> Vec<(usize, char)>
+{
+  let mut res = Vec::new();
+  let mut chars = regex.char_indices().peekable();
+  let mut in_class = false;
+
+  while let Some((i, c)) = chars.next()
+  {
+    if c == '\\'
+    {
+      chars.next();
+    }
+    else if in_class
+    {
+      if c == '[' && regex[(i + 1)..].starts_with(':')
+      {
+        if let Some(end) = regex[i..].find(":]")
+        {
+          for _ in regex[(i + 1)..(i + end + 2)].chars()
+          {
+            chars.next();
+          }
+        }
+      }
+      else if c == ']'
+      {
+        in_class = false;
+      }
+    }
+    else if c == '['
+    {
+      in_class = true;
+
+      if chars.peek().map(|x| x.1) == Some('^')
+      {
+        chars.next();
+      }
+      if chars.peek().map(|x| x.1) == Some(']')
+      {
+        chars.next();
+      }
+    }
+    else
+    {
+      res.push((i, c));
+    }
+  }
+
+  return res;
+}
+
+/*
+ * Finds the `/`s of a regex that split off trailing context, by char index.
+ * Those are the ones that aren't escaped, in a class or in a group, so an
+ * expanded definition never has one.
+ */
+fn trailing_slashes(regex: &str) -> Vec<usize>
+{
+  let mut res = Vec::new();
+  let mut group_depth = 0;
+
+  for (i, c) in plain_chars(regex)
+  {
+    if c == '('
+    {
+      group_depth += 1;
+    }
+    else if c == ')' && group_depth > 0
+    {
+      group_depth -= 1;
+    }
+    else if c == '/' && group_depth == 0
+    {
+      res.push(regex[..i].chars().count());
+    }
+  }
+
+  return res;
+}
+
+/*
+ * Takes the backslash out of every `\/`, along with its origin. Slashes
+ * are escaped to keep them from splitting off trailing context, but the
+ * regex parser doesn't know the escape.
+ */
+fn unescape_slashes(regex: &str, origins: &[usize]) -> (String, Vec<usize>)
+{
+  let chars: Vec<char> = regex.chars().collect();
+  let mut text = String::new();
+  let mut kept = Vec::new();
+  let mut escaped = false;
+
+  for (i, &c) in chars.iter().enumerate()
+  {
+    if !escaped && c == '\\' && chars.get(i + 1) == Some(&'/')
+    {
+      continue;
+    }
+
+    escaped = !escaped && c == '\\';
+    text.push(c);
+    kept.push(origins[i]);
+  }
+
+  return (text, kept);
+}
+
+/*
+ * The literal text of a pattern as a regex.
+ */
+fn pattern_text(pattern: &Pattern) -> String
+{
+  match pattern.kind
+  {
+    PatternKind::Literal => regex_syntax::quote(&pattern.text),
+    _ => pattern.text.clone(),
+  }
+}
+
+/*
+ * Where the char at `index` of a pattern's text came from. Literals are
+ * quoted before they're parsed, so they always point at their start.
+ */
+fn pattern_span(pattern: &Pattern, index: usize) -> Span
+{
+  if pattern.kind == PatternKind::Literal
+  {
+    return Span { start: pattern.span.start, end: pattern.span.start + 1 };
+  }
+
+  match pattern.offsets.get(index)
+  {
+    Some(&x) => Span { start: x, end: x + 1 },
+    None => Span { start: pattern.span.end, end: pattern.span.end },
+  }
+}
+
+/*
+ * The text of a file that has been read, kept so diagnostics can show the
+ * line they're about.
+ */
+struct SourceFile
+{
+  name : Option<String>,
+  text : String,
+  included_from : Vec<(Option<String>, usize)>,
+}
+impl SourceFile
+{
+  fn at(&self, span: Span) -> Location
+  {
+    let line_start = self.text[..span.start].rfind('\n').map(|x| x + 1).unwrap_or(0);
+    let line_end = self.text[span.start..].find('\n').map(|x| span.start + x).unwrap_or(self.text.len());
+    let end = span.end.min(line_end).max(span.start);
+
+    Location
+    {
+      file: self.name.clone(),
+      line: self.text[..span.start].matches('\n').count() + 1,
+      column: self.text[line_start..span.start].chars().count() + 1,
+      snippet: String::from(self.text[line_start..line_end].trim_end_matches('\r')),
+      length: self.text[span.start..end].chars().count(),
+      included_from: self.included_from.clone(),
+    }
+  }
+}
+
+/*
+ * A token declaration that has been read, but whose pattern can't be
+ * parsed until every definition has been read.
+ */
+struct Declaration
+{
+  file : usize,
+  span : Span,
+  namespace : String,
+  name : Ident,
+  qualified : String,
+  doc : Vec<String>,
+  mode : usize,
+  attributes : Vec<Attribute>,
+  pattern : Pattern,
+  keyword_of : Option<Ident>,
+}
+
+/*
+ * A named sub-pattern, declared as `name = pattern` and referenced from
+ * other patterns as `{name}`. Definitions are expanded textually before the
+ * regex is parsed, so they never become tokens themselves.
+ */
+struct Definition
+{
+  file : usize,
+  name : Ident,
+  namespace : String,
+  pattern : Pattern,
+}
+
+/*
+ * Expands `{name}` references. Broken holds the definitions that have
+ * already been reported, so using one doesn't give another error, and used
+ * collects every definition that has been expanded.
+ */
+struct Expander<'a>
+{
+  definitions : &'a HashMap<String, Definition>,
+  files : &'a [SourceFile],
+  broken : &'a HashSet<String>,
+  used : HashSet<String>,
+  stack : Vec<String>,
+}
+impl<'a> Expander<'a>
+{
+  /*
+   * Finds the definition a `{name}` reference refers to. Names are looked up
+   * in the namespace of the pattern they appear in first, and then globally,
+   * so an imported file can use its own definitions unqualified.
+   */
+  fn resolve(&self, name: &str, namespace: &str) -> Option<(String, &'a Definition)>
+  {
+    let local = format!("{}{}", namespace, name);
+
+    if let Some(x) = self.definitions.get(&local)
+    {
+      return Some((local, x));
+    }
+
+    return self.definitions.get(name).map(|x| (String::from(name), x));
+  }
+
+  /*
+   * Replaces every `{name}` reference in a pattern with the (recursively
+   * expanded) definition, wrapped in a non-capturing group. Counted repetitions
+   * like `{2,3}` don't look like identifiers and are left alone, as are braces
+   * that are escaped or inside a character class.
+   *
+   * Along with the regex, gives back the index of the char of the pattern
+   * each char of it came from, where a whole expansion comes from the `{`
+   * of its reference. Fails with None when a broken definition is used.
+   */
+  fn expand(&mut self, pattern: &Pattern, file: usize, namespace: &str) -> Result<(String, Vec<usize>), Option<(Location, String)>>
+  {
+    let text = pattern_text(pattern);
+    let braces: HashSet<usize> = plain_chars(&text).into_iter().filter(|x| x.1 == '{').map(|x| x.0).collect();
+    let mut res = String::new();
+    let mut origins = Vec::new();
+    let mut index = 0;
+    let mut rest = &text as &str;
+
+    while let Some(c) = rest.chars().next()
+    {
+      let position = text.len() - rest.len();
+
+      rest = &rest[c.len_utf8()..];
+      index += 1;
+
+      if braces.contains(&position)
+      {
+        if let Some(end) = rest.find('}')
+        {
+          let name = &rest[..end];
+
+          if name.split('.').all(|x| valid_identifier(x) && !x.is_empty())
+          {
+            let reference = index - 1;
+
+            rest = &rest[(end + 1)..];
+            index += name.chars().count() + 1;
+
+            let (key, definition) = match self.resolve(name, namespace)
+            {
+              Some(x) => x,
+              None =>
+              {
+                let location = self.files[file].at(pattern_span(pattern, reference));
+                return Err(Some((location, format!("undefined definition {{{}}}.", name))));
+              }
+            };
+
+            if self.broken.contains(&key)
+            {
+              return Err(None);
+            }
+
+            if let Some(pos) = self.stack.iter().position(|x| *x == key)
+            {
+              let mut cycle = self.stack[pos..].to_vec();
+              cycle.push(key.clone());
+
+              let location = self.files[definition.file].at(definition.name.span);
+              return Err(Some((location, format!("definition {} refers to itself ({}).", key, cycle.join(" -> ")))));
+            }
+
+            self.used.insert(key.clone());
+
+            self.stack.push(key);
+            let (expanded, _) = self.expand(&definition.pattern, definition.file, &definition.namespace)?;
+            self.stack.pop();
+
+            let length = expanded.chars().count() + 4;
+
+            res.push_str("(?:");
+            res.push_str(&expanded);
+            res.push_str(")");
+            origins.extend((0..length).map(|_| reference));
+            continue;
+          }
+        }
+      }
+
+      res.push(c);
+      origins.push(index - 1);
+    }
+
+    return Ok((res, origins));
+  }
+
+  /*
+   * Expands and parses a pattern, ignoring case everywhere in it if nocase
+   * is set. Regex errors point at the char of the pattern they're about.
+   */
+  fn parse(&mut self, pattern: &Pattern, file: usize, namespace: &str, kind: &str, nocase: bool) -> Result<Expr, Option<(Location, String)>>
+  {
+    let (regex, origins) = self.expand(pattern, file, namespace)?;
+
+    return self.parse_expanded(&regex, &origins, pattern, file, kind, nocase);
+  }
+
+  /*
+   * Expands and parses the pattern of a token, splitting off its trailing
+   * context if it has any. Literals never do.
+   */
+  fn parse_token(&mut self, pattern: &Pattern, file: usize, namespace: &str, nocase: bool) -> Result<(Expr, Option<Expr>), Option<(Location, String)>>
+  {
+    let kind = "token declaration";
+    let (regex, origins) = self.expand(pattern, file, namespace)?;
+
+    let slashes = match pattern.kind
+    {
+      PatternKind::Literal => Vec::new(),
+      _ => trailing_slashes(&regex),
+    };
+
+    let split = match slashes.first()
+    {
+      Some(&x) => x,
+      None => return Ok((self.parse_expanded(&regex, &origins, pattern, file, kind, nocase)?, None)),
+    };
+
+    let location = |index: usize| self.files[file].at(pattern_span(pattern, origins[index]));
+
+    if let Some(&x) = slashes.get(1)
+    {
+      return Err(Some((location(x), String::from("invalid token declaration. a token can only have one trailing context. use `\\/` or `[/]` to match a slash."))));
+    }
+
+    let chars: Vec<char> = regex.chars().collect();
+
+    if split == 0 || split == chars.len() - 1
+    {
+      return Err(Some((location(split), String::from("invalid token declaration. trailing context needs a pattern on both sides of the `/`. use `\\/` or `[/]` to match a slash."))));
+    }
+
+    let head: String = chars[..split].iter().collect();
+    let tail: String = chars[(split + 1)..].iter().collect();
+
+    let regex = self.parse_expanded(&head, &origins[..split], pattern, file, kind, nocase)?;
+    let trailing = self.parse_expanded(&tail, &origins[(split + 1)..], pattern, file, kind, nocase)?;
+
+    return Ok((regex, Some(trailing)));
+  }
+
+  /*
+   * Parses an expanded regex, ignoring case everywhere in it if nocase is
+   * set. Errors point at the char of the pattern they're about.
+   *
+   * Like in flex, `^` and `$` match at the start and end of a line, so
+   * patterns are parsed in multi-line mode. `\A` and `\z` match at the
+   * start and end of the text.
+   */
+  fn parse_expanded(&self, regex: &str, origins: &[usize], pattern: &Pattern, file: usize, kind: &str, nocase: bool) -> Result<Expr, Option<(Location, String)>>
+  {
+    let (regex, origins) = unescape_slashes(regex, origins);
+
+    match ExprBuilder::new().case_insensitive(nocase).multi_line(true).parse(&regex)
+    {
+      Ok(x) => Ok(x),
+      Err(e) =>
+      {
+        let index = origins.get(e.position()).cloned().unwrap_or(pattern.text.chars().count());
+        let location = self.files[file].at(pattern_span(pattern, index));
+
+        Err(Some((location, format!("invalid {}. bad pattern. {}", kind, regex_message(&e)))))
+      }
+    }
+  }
+}
+
+fn apply_attributes(token: &mut Token, declaration: &Declaration, modes: &[String], file: &SourceFile, diagnostics: &mut Diagnostics)
+{
+  for attribute in declaration.attributes.iter()
+  {
+    let key = &attribute.key.name as &str;
+    let value = attribute.value.as_ref();
+
+    let action = match (key, value)
+    {
+      ("skip", None) =>
+      {
+        token.skip = true;
+        continue;
+      }
+      ("nocase", None) =>
+      {
+        // Keywords are compared at runtime, where only ASCII letters are
+        // folded, so any other letter with a case would be compared exactly
+
+        let cased = |c: char| !c.is_ascii() && (c.is_lowercase() || c.is_uppercase());
+
+        if declaration.keyword_of.is_some() && declaration.name.name.chars().any(cased)
+        {
+          let message = format!("keyword {} can't ignore case. only ASCII letters are compared without case.", declaration.name.name);
+          diagnostics.error(Some(file.at(declaration.name.span)), &message);
+        }
+
+        token.nocase = true;
+        continue;
+      }
+      ("not-followed-by", Some(_)) =>
+      {
+        // The class is parsed along with the pattern, but keywords aren't
+        // matched by the automaton and trailing context already says what
+        // has to come next
+
+        if declaration.keyword_of.is_some()
+        {
+          diagnostics.error(Some(file.at(attribute.span)), "keywords can't have not-followed-by. give it to their base token instead.");
+        }
+        else if token.trailing.is_some()
+        {
+          diagnostics.error(Some(file.at(attribute.span)), "a token with trailing context can't have not-followed-by.");
+        }
+        continue;
+      }
+      ("priority", Some(value)) =>
+      {
+        match value.text.parse()
+        {
+          Ok(x) => token.priority = x,
+          Err(_) => diagnostics.error(Some(file.at(value.span)), &format!("invalid priority {}.", value.text)),
+        }
+        continue;
+      }
+      ("switch", Some(mode)) | ("push", Some(mode)) =>
+      {
+        // Modes are looked up in the declaration's namespace first
+
+        let local = format!("{}{}", declaration.namespace.replace('.', "_"), mode.text);
+
+        let index = match modes.iter().position(|x| *x == local).or(modes.iter().position(|x| *x == mode.text))
+        {
+          Some(x) => x,
+          None =>
+          {
+            diagnostics.error(Some(file.at(mode.span)), &format!("unknown mode {}.", mode.text));
+            continue;
+          }
+        };
+
+        match key
+        {
+          "switch" => Action::Switch(index),
+          _ => Action::Push(index),
+        }
+      }
+      ("pop", None) => Action::Pop,
+      _ =>
+      {
+        diagnostics.error(Some(file.at(attribute.span)), &format!("unknown attribute {}.", key));
+        continue;
+      }
+    };
+
+    if token.action != Action::None
+    {
+      diagnostics.error(Some(file.at(attribute.span)), "a token can only have one of switch, push or pop.");
+      continue;
+    }
+    token.action = action;
+  }
+}
+
+/*
+ * A file being read. Everything declared in an imported file is put in a
+ * namespace, like `numbers.`, and tokens declared outside of a mode block
+ * go in the mode the file was included from.
+ */
+struct Source
+{
+  file : usize,
+  path : Option<PathBuf>,
+  namespace : String,
+  mode : usize,
+}
+
+/*
+ * Collects declarations from a .rlx file and everything it includes. The
+ * patterns are only parsed once everything has been read, so definitions
+ * can be used before they're declared. Every problem is reported to the
+ * diagnostics, and whatever was broken is left out.
+ */
+struct Reader<'a>
+{
+  spec : Spec,
+  files : Vec<SourceFile>,
+  definitions : HashMap<String, Definition>,
+  declarations : Vec<Declaration>,
+  included : HashSet<(PathBuf, String)>,
+  stack : Vec<PathBuf>,
+  diagnostics : &'a mut Diagnostics,
+}
+impl<'a> Reader<'a>
+{
+  fn error(&mut self, file: usize, span: Span, message: &str)
+  {
+    let location = self.files[file].at(span);
+    self.diagnostics.error(Some(location), message);
+  }
+
+  fn add_file(&mut self, name: Option<String>, text: String, included_from: Vec<(Option<String>, usize)>) -> Vec<Item>
+  {
+    let (items, errors) = parser::parse(&text);
+
+    self.files.push(SourceFile
+    {
+      name: name,
+      text: text,
+      included_from: included_from,
+    });
+
+    let file = self.files.len() - 1;
+
+    for e in errors.iter()
+    {
+      self.error(file, e.span, &e.message);
+    }
+
+    return items;
+  }
+
+  fn read(&mut self, items: &[Item], source: &Source, mode: Option<usize>)
+  {
+    let file = source.file;
+    let prefix = source.namespace.replace('.', "_");
+
+    for item in items.iter()
+    {
+      match *item
+      {
+        Item::Include(ref include) =>
+        {
+          self.include(include, source, mode.unwrap_or(source.mode));
+        }
+        Item::Mode(ref block) =>
+        {
+          if mode.is_some()
+          {
+            self.error(file, block.span, "modes can't be nested.");
+            continue;
+          }
+          if !valid_identifier(&block.name.name)
+          {
+            self.error(file, block.name.span, "invalid mode declaration. bad identifier.");
+            continue;
+          }
+
+          let name = format!("{}{}", prefix, block.name.name);
+
+          let index = match self.spec.modes.iter().position(|x| *x == name)
+          {
+            Some(x) => x,
+            None =>
+            {
+              self.spec.modes.push(name);
+              self.spec.modes.len() - 1
+            }
+          };
+
+          self.read(&block.items, source, Some(index));
+        }
+        Item::Keywords(ref block) =>
+        {
+          // Every word in a keyword block is a token of its own
+
+          if !valid_identifier(&block.base.name)
+          {
+            self.error(file, block.span, "invalid keyword declaration. bad identifier.");
+            continue;
+          }
+
+          for keyword in block.keywords.iter()
+          {
+            let word = &keyword.name;
+
+            if !valid_identifier(&word.name)
+            {
+              self.error(file, word.span, &format!("invalid keyword {}.", word.name));
+              continue;
+            }
+
+            self.declarations.push(Declaration
+            {
+              file: file,
+              span: word.span,
+              namespace: source.namespace.clone(),
+              name: word.clone(),
+              qualified: format!("{}{}", prefix, word.name),
+              doc: keyword.doc.clone(),
+              mode: mode.unwrap_or(source.mode),
+              attributes: block.attributes.clone(),
+              pattern: Pattern
+              {
+                kind: PatternKind::Literal,
+                text: word.name.clone(),
+                offsets: vec![word.span.start; word.name.chars().count()],
+                span: word.span,
+              },
+              keyword_of: Some(block.base.clone()),
+            });
+          }
+        }
+        Item::Definition(ref definition) =>
+        {
+          if !valid_identifier(&definition.name.name)
+          {
+            self.error(file, definition.name.span, "invalid definition. bad identifier.");
+            continue;
+          }
+          if let Some(attribute) = definition.attributes.first()
+          {
+            self.error(file, attribute.span, "definitions can't have attributes.");
+            continue;
+          }
+          if mode.is_some()
+          {
+            self.error(file, definition.span, "definitions can't be declared inside a mode.");
+            continue;
+          }
+
+          let key = format!("{}{}", source.namespace, definition.name.name);
+
+          if self.definitions.contains_key(&key)
+          {
+            self.error(file, definition.name.span, &format!("duplicate definition {}.", key));
+            continue;
+          }
+
+          self.definitions.insert(key, Definition
+          {
+            file: file,
+            name: definition.name.clone(),
+            namespace: source.namespace.clone(),
+            pattern: definition.pattern.clone(),
+          });
+        }
+        Item::Token(ref token) =>
+        {
+          if !valid_identifier(&token.name.name)
+          {
+            self.error(file, token.name.span, "invalid token declaration. bad identifier.");
+            continue;
+          }
+
+          self.declarations.push(Declaration
+          {
+            file: file,
+            span: token.span,
+            namespace: source.namespace.clone(),
+            name: token.name.clone(),
+            qualified: format!("{}{}", prefix, token.name.name),
+            doc: token.doc.clone(),
+            mode: mode.unwrap_or(source.mode),
+            attributes: token.attributes.clone(),
+            pattern: token.pattern.clone(),
+            keyword_of: None,
+          });
+        }
+      }
+    }
+  }
+
+  /*
+   * Reads an included file. Paths are relative to the including file, or
+   * the working directory for stdin. A file is only read once per namespace,
+   * so several files can share a common include.
+   */
+  fn include(&mut self, include: &ast::Include, source: &Source, mode: usize)
+  {
+    let file = source.file;
+
+    if let Some(ref alias) = include.alias
+    {
+      if !valid_identifier(&alias.name)
+      {
+        self.error(file, alias.span, "invalid import. bad identifier.");
+        return;
+      }
+    }
+
+    let path = match source.path
+    {
+      Some(ref path) => path.parent().unwrap_or(Path::new("")).join(&include.path),
+      None => PathBuf::from(&include.path),
+    };
+
+    let canonical = match fs::canonicalize(&path)
+    {
+      Ok(x) => x,
+      Err(_) => return self.error(file, include.span, &format!("error opening file {}.", path.display())),
+    };
+
+    if let Some(pos) = self.stack.iter().position(|x| *x == canonical)
+    {
+      let mut cycle: Vec<String> = self.stack[pos..].iter().map(|x| x.display().to_string()).collect();
+      cycle.push(canonical.display().to_string());
+
+      return self.error(file, include.span, &format!("include cycle ({}).", cycle.join(" -> ")));
+    }
+
+    let namespace = match include.alias
+    {
+      Some(ref alias) => format!("{}{}.", source.namespace, alias.name),
+      None => source.namespace.clone(),
+    };
+
+    if !self.included.insert((canonical.clone(), namespace.clone()))
+    {
+      return;
+    }
+
+    let text = match fs::read_to_string(&path)
+    {
+      Ok(x) => x,
+      Err(_) => return self.error(file, include.span, &format!("error opening file {}.", path.display())),
+    };
+
+    let location = self.files[file].at(include.span);
+    let mut included_from = vec![(location.file, location.line)];
+    included_from.extend(location.included_from);
+
+    let items = self.add_file(Some(path.display().to_string()), text, included_from);
+
+    let inner = Source
+    {
+      file: self.files.len() - 1,
+      path: Some(path),
+      namespace: namespace,
+      mode: mode,
+    };
+
+    self.stack.push(canonical);
+    self.read(&items, &inner, None);
+    self.stack.pop();
+  }
+
+  fn finish(mut self) -> Option<Spec>
+  {
+    let mut broken = HashSet::new();
+
+    // Check every definition on its own, so a bad one is reported where it's
+    // declared instead of at each token that uses it
+
+    let mut names: Vec<String> = self.definitions.keys().cloned().collect();
+    names.sort_by_key(|x|
+    {
+      let definition = self.definitions.get(x).unwrap();
+      (definition.file, definition.name.span.start)
+    });
+
+    for name in names.iter()
+    {
+      let definition = self.definitions.get(name).unwrap();
+
+      let result =
+      {
+        let mut expander = Expander
+        {
+          definitions: &self.definitions,
+          files: &self.files,
+          broken: &broken,
+          used: HashSet::new(),
+          stack: vec![name.clone()],
+        };
+
+        expander.parse(&definition.pattern, definition.file, &definition.namespace, "definition", false)
+      };
+
+      if let Err(e) = result
+      {
+        if let Some((location, message)) = e
+        {
+          self.diagnostics.error(Some(location), &message);
+        }
+        broken.insert(name.clone());
+      }
+    }
+
+    // Handle the regexes
+
+    let mut used = HashSet::new();
+    let mut tokens: Vec<(&Declaration, Token)> = Vec::new();
+
+    for declaration in self.declarations.iter()
+    {
+      let result =
+      {
+        let mut expander = Expander
+        {
+          definitions: &self.definitions,
+          files: &self.files,
+          broken: &broken,
+          used: HashSet::new(),
+          stack: Vec::new(),
+        };
+
+        let nocase = declaration.attributes.iter().any(|x| x.key.name == "nocase" && x.value.is_none());
+        let result = expander.parse_token(&declaration.pattern, declaration.file, &declaration.namespace, nocase);
+
+        // A not-followed-by class can use definitions too, and ignores
+        // case along with the pattern
+
+        let follow = declaration.attributes.iter()
+          .find(|x| x.key.name == "not-followed-by")
+          .and_then(|x| x.value.as_ref())
+          .map(|x| (x, expander.parse(x, declaration.file, &declaration.namespace, "not-followed-by", nocase)));
+
+        used.extend(expander.used);
+        (result, follow)
+      };
+
+      let (regex, trailing) = match result.0
+      {
+        Ok(x) => x,
+        Err(Some((location, message))) =>
+        {
+          self.diagnostics.error(Some(location), &message);
+          continue;
+        }
+        Err(None) => continue,
+      };
+
+      let not_followed_by = match result.1
+      {
+        Some((_, Ok(ref x))) if matches_one_char(x) => Some(x.clone()),
+        Some((pattern, Ok(_))) =>
+        {
+          let location = self.files[declaration.file].at(pattern.span);
+          self.diagnostics.error(Some(location), "invalid not-followed-by. it has to match a single char, like `[a-z]`.");
+          continue;
+        }
+        Some((_, Err(Some((location, message))))) =>
+        {
+          self.diagnostics.error(Some(location), &message);
+          continue;
+        }
+        Some((_, Err(None))) => continue,
+        None => None,
+      };
+
+      if matches_empty(&regex)
+      {
+        let location = self.files[declaration.file].at(declaration.span);
+        self.diagnostics.error(Some(location), &format!("token {} matches the empty string.", declaration.qualified));
+        continue;
+      }
+
+      if tokens.iter().any(|x| x.1.name == declaration.qualified)
+      {
+        let location = self.files[declaration.file].at(declaration.name.span);
+        self.diagnostics.error(Some(location), &format!("duplicate token {}.", declaration.qualified));
+        continue;
+      }
+
+      let mut token = Token
+      {
+        name: declaration.qualified.clone(),
+        doc: declaration.doc.clone(),
+        regex: regex,
+        trailing: trailing,
+        not_followed_by: not_followed_by,
+        skip: false,
+        nocase: false,
+        priority: 0,
+        mode: declaration.mode,
+        action: Action::None,
+        keyword: None,
+      };
+
+      apply_attributes(&mut token, declaration, &self.spec.modes, &self.files[declaration.file], self.diagnostics);
+
+      tokens.push((declaration, token));
+    }
+
+    // Definitions that nothing uses are probably a mistake
+
+    for name in names.iter()
+    {
+      if !used.contains(name) && !broken.contains(name)
+      {
+        let definition = self.definitions.get(name).unwrap();
+        let location = self.files[definition.file].at(definition.name.span);
+
+        self.diagnostics.warning(Some(location), &format!("definition {} is never used.", name));
+      }
+    }
+
+    // Link keywords to their base tokens, which are looked up in the
+    // keyword's namespace first. A keyword is only ever looked up when its
+    // base matches, so the base has to be able to match its text, in the
+    // same mode
+
+    let mut reported = HashSet::new();
+    let mut bases: HashMap<usize, Option<Dfa>> = HashMap::new();
+
+    for i in 0..tokens.len()
+    {
+      let declaration = tokens[i].0;
+
+      if let Some(ref base) = declaration.keyword_of
+      {
+        let local = format!("{}{}", declaration.namespace.replace('.', "_"), base.name);
+
+        let index =
+        {
+          let find = |name: &str| tokens.iter().position(|x| x.1.name == name && x.0.keyword_of.is_none());
+          find(&local).or(find(&base.name))
+        };
+
+        match index
+        {
+          Some(index) =>
+          {
+            let location = self.files[declaration.file].at(declaration.name.span);
+            let base_token = &tokens[index].1;
+
+            if base_token.mode != declaration.mode
+            {
+              let message = format!("keyword {} is in mode {}, but its base token {} is in mode {}.",
+                declaration.name.name, self.spec.modes[declaration.mode], base_token.name, self.spec.modes[base_token.mode]);
+              self.diagnostics.error(Some(location), &message);
+              continue;
+            }
+
+            // A base the automaton can't be built for is reported when the
+            // lexer is compiled
+
+            let dfa = bases.entry(index).or_insert_with(||
+              Ndfa::from_regex(&base_token.regex).and_then(|x| Dfa::from_ndfa(&x)).ok());
+
+            // Nocase keywords are compared with ASCII letters folded, so
+            // any way of writing them will do
+
+            let text = &declaration.name.name;
+            let spellings = if tokens[i].1.nocase
+            {
+              vec![text.clone(), text.to_ascii_lowercase(), text.to_ascii_uppercase()]
+            }
+            else
+            {
+              vec![text.clone()]
+            };
+
+            if dfa.as_ref().map_or(false, |x| spellings.iter().all(|y| x.accepts(y).is_none()))
+            {
+              let message = format!("keyword {} can never be matched, since its base token {} doesn't match it.", declaration.name.name, base_token.name);
+              self.diagnostics.error(Some(location), &message);
+              continue;
+            }
+
+            tokens[i].1.keyword = Some(Keyword
+            {
+              base: index,
+              text: declaration.name.name.clone(),
+            });
+          }
+          None =>
+          {
+            if reported.insert((declaration.file, base.span.start))
+            {
+              let location = self.files[declaration.file].at(base.span);
+              self.diagnostics.error(Some(location), &format!("unknown keyword base token {}.", base.name));
+            }
+          }
+        }
+      }
+    }
+
+    if self.diagnostics.has_errors()
+    {
+      return None;
+    }
+
+    self.spec.tokens = tokens.into_iter().map(|x| x.1).collect();
+    return Some(self.spec);
+  }
+}
+
+/*
+ * Parses the text of a .rlx file. The file's path, if there is one, is
+ * used to find included files and in diagnostics. Gives back None if there
+ * were any errors, which along with any warnings are added to diagnostics.
+ */
+pub fn parse(text: &str, file: Option<&Path>, diagnostics: &mut Diagnostics) -> Option<Spec>
+{
+  let mut reader = Reader
+  {
+    spec: Spec { tokens: Vec::new(), modes: vec![String::from("default")] },
+    files: Vec::new(),
+    definitions: HashMap::new(),
+    declarations: Vec::new(),
+    included: HashSet::new(),
+    stack: Vec::new(),
+    diagnostics: diagnostics,
+  };
+
+  if let Some(canonical) = file.and_then(|x| fs::canonicalize(x).ok())
+  {
+    reader.included.insert((canonical.clone(), String::new()));
+    reader.stack.push(canonical);
+  }
+
+  let items = reader.add_file(file.map(|x| x.display().to_string()), String::from(text), Vec::new());
+
+  let source = Source
+  {
+    file: 0,
+    path: file.map(|x| x.to_path_buf()),
+    namespace: String::new(),
+    mode: 0,
+  };
+
+  reader.read(&items, &source, None);
+
+  return reader.finish();
+}
@@ -0,0 +1,782 @@
+use super::ast::*;
+use super::plain_chars;
+
+/*
+ * The tokenizer and parser for .rlx files. Most of the format is made of
+ * small tokens separated by whitespace and `#` comments, but a pattern
+ * after a `:` or `=` is read straight from the text, since a regex can
+ * contain just about anything.
+ */
+
+pub struct SyntaxError
+{
+  pub span : Span,
+  pub message : String,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Kind
+{
+  Ident(String),
+  Str(String),
+  Doc(String),
+  Colon,
+  Equals,
+  Comma,
+  LParen,
+  RParen,
+  LBrace,
+  RBrace,
+  Unknown(char),
+  Eof,
+}
+
+#[derive(Clone, Debug)]
+struct Lexeme
+{
+  kind : Kind,
+  span : Span,
+}
+
+fn error<T>(start: usize, end: usize, message: &str) -> Result<T, SyntaxError>
+{
+  Err(SyntaxError
+  {
+    span: Span { start: start, end: end },
+    message: String::from(message),
+  })
+}
+
+fn is_ident_start(c: char) -> bool
+{
+  c.is_alphanumeric() || c == '_'
+}
+
+fn is_ident_char(c: char) -> bool
+{
+  c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+/*
+ * Removes a trailing `#` comment from a line. A `#` only starts a comment
+ * when it begins the line or follows whitespace, and isn't escaped or
+ * inside a character class, so patterns like `\#` and `[ #]` still work.
+ */
+fn strip_comment(line: &str) -> &str
+{
+  let mut after_space = true;
+  let mut next = 0;
+
+  for (i, c) in plain_chars(line)
+  {
+    // Escapes and classes in between don't count as whitespace
+
+    after_space = after_space && i == next;
+
+    if c == '#' && after_space
+    {
+      return &line[..i];
+    }
+
+    after_space = c.is_whitespace();
+    next = i + c.len_utf8();
+  }
+
+  return line;
+}
+
+/*
+ * Parses a string literal, like `"+="`, at the start of a line. Gives back
+ * the text and the number of bytes it took up, or None if it isn't a well
+ * formed string.
+ */
+fn parse_literal(line: &str) -> Option<(String, usize)>
+{
+  if !line.starts_with('"')
+  {
+    return None;
+  }
+
+  let mut res = String::new();
+  let mut chars = line.char_indices().skip(1);
+
+  while let Some((i, c)) = chars.next()
+  {
+    match c
+    {
+      '"' => return Some((res, i + 1)),
+      '\n' => return None,
+      '\\' =>
+      {
+        res.push(match chars.next()
+        {
+          Some((_, '\\')) => '\\',
+          Some((_, '"')) => '"',
+          Some((_, 'n')) => '\n',
+          Some((_, 'r')) => '\r',
+          Some((_, 't')) => '\t',
+          _ => return None,
+        });
+      }
+      _ => res.push(c),
+    }
+  }
+
+  return None;
+}
+
+fn literal(text: String, start: usize, end: usize) -> Pattern
+{
+  Pattern
+  {
+    kind: PatternKind::Literal,
+    offsets: vec![start; text.chars().count()],
+    text: text,
+    span: Span { start: start, end: end },
+  }
+}
+
+fn regex(text: &str, start: usize) -> Pattern
+{
+  Pattern
+  {
+    kind: PatternKind::Regex,
+    text: String::from(text),
+    offsets: text.char_indices().map(|(i, _)| start + i).collect(),
+    span: Span { start: start, end: start + text.len() },
+  }
+}
+
+struct Parser<'a>
+{
+  text : &'a str,
+  pos : usize,
+  peeked : Option<Lexeme>,
+  errors : Vec<SyntaxError>,
+}
+impl<'a> Parser<'a>
+{
+  fn current(&self) -> Option<char>
+  {
+    self.text[self.pos..].chars().next()
+  }
+
+  fn line_end(&self) -> usize
+  {
+    match self.text[self.pos..].find('\n')
+    {
+      Some(x) => self.pos + x,
+      None => self.text.len(),
+    }
+  }
+
+  fn skip_spaces(&mut self)
+  {
+    while let Some(c) = self.current()
+    {
+      if c != ' ' && c != '\t'
+      {
+        break;
+      }
+      self.pos += 1;
+    }
+  }
+
+  // Tokenizer
+
+  /*
+   * Skips whitespace and comments, but not doc comments.
+   */
+  fn skip_trivia(&mut self)
+  {
+    loop
+    {
+      match self.current()
+      {
+        Some(c) if c.is_whitespace() => self.pos += c.len_utf8(),
+        Some('#') => self.pos = self.line_end(),
+        _ => break,
+      }
+    }
+  }
+
+  fn scan(&mut self) -> Result<Lexeme, SyntaxError>
+  {
+    self.skip_trivia();
+
+    let start = self.pos;
+
+    let c = match self.current()
+    {
+      Some(c) => c,
+      None => return Ok(Lexeme { kind: Kind::Eof, span: Span { start: start, end: start } }),
+    };
+
+    let kind = if self.text[start..].starts_with("///")
+    {
+      let end = self.line_end();
+      let text = &self.text[(start + 3)..end];
+      let text = if text.starts_with(' ') { &text[1..] } else { text };
+
+      self.pos = end;
+      Kind::Doc(String::from(text.trim_end()))
+    }
+    else if is_ident_start(c)
+    {
+      let length = self.text[start..].find(|c| !is_ident_char(c)).unwrap_or(self.text.len() - start);
+
+      self.pos += length;
+      Kind::Ident(String::from(&self.text[start..self.pos]))
+    }
+    else if c == '"'
+    {
+      match parse_literal(&self.text[start..])
+      {
+        Some((text, length)) =>
+        {
+          self.pos += length;
+          Kind::Str(text)
+        }
+        None => return error(start, self.line_end(), "invalid string. missing closing quote or bad escape."),
+      }
+    }
+    else
+    {
+      self.pos += c.len_utf8();
+
+      match c
+      {
+        ':' => Kind::Colon,
+        '=' => Kind::Equals,
+        ',' => Kind::Comma,
+        '(' => Kind::LParen,
+        ')' => Kind::RParen,
+        '{' => Kind::LBrace,
+        '}' => Kind::RBrace,
+        _ => Kind::Unknown(c),
+      }
+    };
+
+    return Ok(Lexeme { kind: kind, span: Span { start: start, end: self.pos } });
+  }
+
+  fn peek(&mut self) -> Result<Lexeme, SyntaxError>
+  {
+    if self.peeked.is_none()
+    {
+      self.peeked = Some(self.scan()?);
+    }
+
+    return Ok(self.peeked.clone().unwrap());
+  }
+
+  fn next(&mut self) -> Result<Lexeme, SyntaxError>
+  {
+    match self.peeked.take()
+    {
+      Some(x) => Ok(x),
+      None => self.scan(),
+    }
+  }
+
+  fn expect(&mut self, kind: Kind, message: &str) -> Result<Lexeme, SyntaxError>
+  {
+    let next = self.next()?;
+
+    if next.kind != kind
+    {
+      return error(next.span.start, next.span.end, message);
+    }
+
+    return Ok(next);
+  }
+
+  fn ident(&mut self, message: &str) -> Result<Ident, SyntaxError>
+  {
+    let next = self.next()?;
+
+    match next.kind
+    {
+      Kind::Ident(name) => Ok(Ident { name: name, span: next.span }),
+      _ => error(next.span.start, next.span.end, message),
+    }
+  }
+
+  // Patterns are read straight from the text, and never peeked at
+
+  /*
+   * Reads a pattern in backticks. A line break inside it is dropped along
+   * with the whitespace around it, so a long regex can be split up. Only
+   * the backtick itself needs escaping, everything else is kept as is.
+   */
+  fn quoted(&mut self) -> Result<Pattern, SyntaxError>
+  {
+    let start = self.pos;
+    let mut text = String::new();
+    let mut offsets = Vec::new();
+    let mut chars = self.text[(start + 1)..].char_indices().map(|(i, c)| (start + 1 + i, c)).peekable();
+
+    while let Some((i, c)) = chars.next()
+    {
+      match c
+      {
+        '`' =>
+        {
+          self.pos = i + 1;
+          return Ok(Pattern { kind: PatternKind::Quoted, text: text, offsets: offsets, span: Span { start: start, end: self.pos } });
+        }
+        '\\' if chars.peek().map(|x| x.1) == Some('`') =>
+        {
+          chars.next();
+          text.push('`');
+          offsets.push(i);
+        }
+        '\\' =>
+        {
+          text.push('\\');
+          offsets.push(i);
+
+          if let Some((j, x)) = chars.next()
+          {
+            text.push(x);
+            offsets.push(j);
+          }
+        }
+        '\n' =>
+        {
+          let length = text.trim_end().len();
+          text.truncate(length);
+          offsets.truncate(text.chars().count());
+
+          while chars.peek().map(|x| x.1.is_whitespace()) == Some(true)
+          {
+            chars.next();
+          }
+        }
+        _ =>
+        {
+          text.push(c);
+          offsets.push(i);
+        }
+      }
+    }
+
+    // Everything up to the end of the file is part of the broken pattern
+
+    self.pos = self.text.len();
+    return error(start, self.pos, "unterminated quoted pattern.");
+  }
+
+  /*
+   * Reads the pattern after a `:` or `=`, which has to start on the same
+   * line. A string literal is only taken as one when nothing but a comment
+   * follows it, so `"[^"]*"` is still a regex matching a quoted string.
+   */
+  fn pattern(&mut self) -> Result<Pattern, SyntaxError>
+  {
+    debug_assert!(self.peeked.is_none());
+
+    self.skip_spaces();
+
+    let start = self.pos;
+    let end = self.line_end();
+    let line = &self.text[start..end];
+
+    if line.starts_with('`')
+    {
+      return self.quoted();
+    }
+
+    if let Some((text, length)) = parse_literal(line)
+    {
+      let rest = line[length..].trim_start();
+
+      if rest.is_empty() || rest.starts_with('#')
+      {
+        self.pos = start + length;
+        return Ok(literal(text, start, self.pos));
+      }
+    }
+
+    let pattern = strip_comment(line).trim_end();
+
+    if pattern.is_empty()
+    {
+      return error(start, end, "expected a pattern.");
+    }
+
+    self.pos = start + pattern.len();
+    return Ok(regex(pattern, start));
+  }
+
+  /*
+   * Reads an attribute value, which ends at a `,` or `)` that isn't
+   * escaped, nested or inside a character class.
+   */
+  fn value(&mut self) -> Result<Pattern, SyntaxError>
+  {
+    debug_assert!(self.peeked.is_none());
+
+    self.skip_spaces();
+
+    let start = self.pos;
+    let line = &self.text[start..self.line_end()];
+
+    if line.starts_with('`')
+    {
+      return self.quoted();
+    }
+
+    if let Some((text, length)) = parse_literal(line)
+    {
+      self.pos = start + length;
+      return Ok(literal(text, start, self.pos));
+    }
+
+    let mut depth = 0;
+    let mut length = line.len();
+
+    for (i, c) in plain_chars(line)
+    {
+      if c == '('
+      {
+        depth += 1;
+      }
+      else if depth > 0 && c == ')'
+      {
+        depth -= 1;
+      }
+      else if depth == 0 && (c == ',' || c == ')')
+      {
+        length = i;
+        break;
+      }
+    }
+
+    let value = line[..length].trim_end();
+
+    if value.is_empty()
+    {
+      return error(start, start + length, "expected a value.");
+    }
+
+    self.pos = start + value.len();
+    return Ok(regex(value, start));
+  }
+
+  // Parser
+
+  fn doc(&mut self) -> Result<(Vec<String>, Option<Span>), SyntaxError>
+  {
+    let mut doc = Vec::new();
+    let mut span = None;
+
+    while let Kind::Doc(text) = self.peek()?.kind
+    {
+      let next = self.next()?;
+
+      span = span.or(Some(next.span));
+      doc.push(text);
+    }
+
+    return Ok((doc, span));
+  }
+
+  fn attributes(&mut self) -> Result<Vec<Attribute>, SyntaxError>
+  {
+    let mut res = Vec::new();
+
+    if self.peek()?.kind != Kind::LParen
+    {
+      return Ok(res);
+    }
+    self.next()?;
+
+    loop
+    {
+      let key = self.ident("invalid attribute list. expected an attribute name.")?;
+      let mut value = None;
+      let mut end = key.span.end;
+
+      if self.peek()?.kind == Kind::Equals
+      {
+        self.next()?;
+
+        let x = self.value()?;
+        end = x.span.end;
+        value = Some(x);
+      }
+
+      res.push(Attribute
+      {
+        span: Span { start: key.span.start, end: end },
+        key: key,
+        value: value,
+      });
+
+      let next = self.next()?;
+
+      match next.kind
+      {
+        Kind::Comma => continue,
+        Kind::RParen => return Ok(res),
+        _ => return error(next.span.start, next.span.end, "invalid attribute list. expected , or )."),
+      }
+    }
+  }
+
+  /*
+   * Reads items up to the end of the file, or the closing brace of a mode
+   * block. A broken item is reported and skipped, so one mistake doesn't
+   * hide the ones after it.
+   */
+  fn items(&mut self, block: Option<Span>) -> Vec<Item>
+  {
+    let mut items = Vec::new();
+
+    loop
+    {
+      if self.peeked.is_none()
+      {
+        self.skip_trivia();
+      }
+
+      let start = self.peeked.as_ref().map(|x| x.span.start).unwrap_or(self.pos);
+
+      match self.entry(block)
+      {
+        Ok(Some(x)) => items.push(x),
+        Ok(None) => return items,
+        Err(e) => self.recover(e, start),
+      }
+    }
+  }
+
+  /*
+   * Skips past a broken item that started at `start`. If the error is on a
+   * later line, that line might start a good item, so parsing picks up
+   * there. Otherwise it picks up on the line after the error.
+   */
+  fn recover(&mut self, e: SyntaxError, start: usize)
+  {
+    let line_start = self.text[..e.span.start].rfind('\n').map(|x| x + 1).unwrap_or(0);
+
+    self.peeked = None;
+    self.pos = match line_start > start
+    {
+      true => line_start,
+      false => self.text[e.span.end..].find('\n').map(|x| e.span.end + x).unwrap_or(self.text.len()),
+    };
+
+    self.errors.push(e);
+  }
+
+  fn report(&mut self, span: Span, message: &str)
+  {
+    self.errors.push(SyntaxError
+    {
+      span: span,
+      message: String::from(message),
+    });
+  }
+
+  fn entry(&mut self, block: Option<Span>) -> Result<Option<Item>, SyntaxError>
+  {
+    let (doc, doc_span) = self.doc()?;
+    let next = self.next()?;
+
+    match next.kind
+    {
+      Kind::Ident(name) => Ok(Some(self.item(Ident { name: name, span: next.span }, doc, doc_span)?)),
+      _ if doc_span.is_some() =>
+      {
+        let span = doc_span.unwrap();
+        error(span.start, span.end, "doc comment is not attached to a token.")
+      }
+      Kind::RBrace if block.is_some() => Ok(None),
+      Kind::RBrace => error(next.span.start, next.span.end, "unexpected }."),
+      Kind::Eof =>
+      {
+        if let Some(span) = block
+        {
+          self.report(span, "mode block is missing a closing }.");
+        }
+        Ok(None)
+      }
+      _ => error(next.span.start, next.span.end, "invalid token declaration."),
+    }
+  }
+
+  fn item(&mut self, name: Ident, doc: Vec<String>, doc_span: Option<Span>) -> Result<Item, SyntaxError>
+  {
+    let next = self.peek()?;
+
+    let directive = match (&name.name as &str, &next.kind)
+    {
+      ("mode", &Kind::Ident(_)) | ("keywords", &Kind::Ident(_)) => true,
+      ("include", &Kind::Str(_)) | ("import", &Kind::Str(_)) => true,
+      _ => false,
+    };
+
+    // Only tokens can be documented
+
+    if let Some(span) = doc_span
+    {
+      if directive || next.kind == Kind::Equals
+      {
+        return error(span.start, span.end, "doc comment is not attached to a token.");
+      }
+    }
+
+    if directive
+    {
+      return match &name.name as &str
+      {
+        "mode" => self.mode(name),
+        "keywords" => self.keywords(name),
+        _ => self.include(name),
+      };
+    }
+
+    let attributes = self.attributes()?;
+    let separator = self.next()?;
+
+    match separator.kind
+    {
+      Kind::Colon =>
+      {
+        let pattern = self.pattern()?;
+
+        Ok(Item::Token(TokenDecl
+        {
+          span: Span { start: name.span.start, end: pattern.span.end },
+          doc: doc,
+          name: name,
+          attributes: attributes,
+          pattern: pattern,
+        }))
+      }
+      Kind::Equals =>
+      {
+        let pattern = self.pattern()?;
+
+        Ok(Item::Definition(DefinitionDecl
+        {
+          span: Span { start: name.span.start, end: pattern.span.end },
+          name: name,
+          attributes: attributes,
+          pattern: pattern,
+        }))
+      }
+      _ => error(separator.span.start, separator.span.end, "invalid token declaration. expected : or =."),
+    }
+  }
+
+  fn mode(&mut self, keyword: Ident) -> Result<Item, SyntaxError>
+  {
+    let name = self.ident("invalid mode declaration. expected a name.")?;
+    let open = self.expect(Kind::LBrace, "invalid mode declaration. expected {.")?;
+    let items = self.items(Some(open.span));
+
+    return Ok(Item::Mode(ModeBlock
+    {
+      name: name,
+      items: items,
+      span: Span { start: keyword.span.start, end: self.pos },
+    }));
+  }
+
+  fn keywords(&mut self, keyword: Ident) -> Result<Item, SyntaxError>
+  {
+    let base = self.ident("invalid keyword declaration. expected a base token.")?;
+    let attributes = self.attributes()?;
+    let open = self.expect(Kind::LBrace, "invalid keyword declaration. expected {.")?;
+    let mut keywords = Vec::new();
+
+    loop
+    {
+      let (doc, doc_span) = self.doc()?;
+      let next = self.next()?;
+
+      if let Kind::Ident(name) = next.kind
+      {
+        keywords.push(KeywordDecl { doc: doc, name: Ident { name: name, span: next.span } });
+        continue;
+      }
+
+      if let Some(span) = doc_span
+      {
+        self.report(span, "doc comment is not attached to a token.");
+      }
+
+      match next.kind
+      {
+        Kind::RBrace => break,
+        Kind::Eof =>
+        {
+          self.report(open.span, "keyword block is missing a closing }.");
+          break;
+        }
+        _ => self.report(next.span, "invalid keyword."),
+      }
+    }
+
+    return Ok(Item::Keywords(KeywordBlock
+    {
+      base: base,
+      attributes: attributes,
+      keywords: keywords,
+      span: Span { start: keyword.span.start, end: self.pos },
+    }));
+  }
+
+  fn include(&mut self, keyword: Ident) -> Result<Item, SyntaxError>
+  {
+    let path = match self.next()?.kind
+    {
+      Kind::Str(x) => x,
+      _ => unreachable!(),
+    };
+
+    let alias = match &keyword.name as &str
+    {
+      "import" =>
+      {
+        let next = self.next()?;
+
+        if next.kind != Kind::Ident(String::from("as"))
+        {
+          return error(next.span.start, next.span.end, "invalid import. expected `as name`.");
+        }
+
+        Some(self.ident("invalid import. expected `as name`.")?)
+      }
+      _ => None,
+    };
+
+    return Ok(Item::Include(Include
+    {
+      path: path,
+      alias: alias,
+      span: Span { start: keyword.span.start, end: self.pos },
+    }));
+  }
+}
+
+/*
+ * Parses the text of a .rlx file into its items, along with every syntax
+ * error found on the way. Broken items are left out.
+ */
+pub fn parse(text: &str) -> (Vec<Item>, Vec<SyntaxError>)
+{
+  let mut parser = Parser
+  {
+    text: text,
+    pos: 0,
+    peeked: None,
+    errors: Vec::new(),
+  };
+
+  let items = parser.items(None);
+  return (items, parser.errors);
+}